@@ -0,0 +1,32 @@
+use leptos::*;
+
+/// Query string parameter carrying the session token, since browsers cannot set headers on a
+/// WebTransport CONNECT request.
+pub const ACCESS_TOKEN_PARAM: &str = "access_token";
+
+/// Issues a short-lived token for a WebTransport session to `path`, or `None` when the server
+/// accepts sessions without one or does not issue tokens to anonymous visitors.
+///
+/// The template has no sign-in, so this is a demo-only bypass: with `auth.issue_anonymous` set,
+/// every visitor of the site gets a token, which defeats token auth. Applications with accounts
+/// should check the user's session here instead and put its id in the `sub` claim.
+#[server(IssueToken, "/api")]
+pub async fn issue_token(path: String) -> Result<Option<String>, ServerFnError> {
+    use crate::webtransport_server::TokenAuth;
+    use std::sync::Arc;
+
+    Ok(use_context::<Arc<TokenAuth>>().map(|auth| auth.issue(None, &path)))
+}
+
+/// Where browsers fetch the SHA-256 hash of the WebTransport server's self-signed certificate,
+/// provided to server functions when the server runs with one.
+#[cfg(feature = "ssr")]
+#[derive(Debug, Clone)]
+pub struct CertificateHashUrl(pub String);
+
+/// Returns the URL serving the hash of the WebTransport server's self-signed certificate, or
+/// `None` when its certificate is trusted by browsers without one.
+#[server(CertificateHashSource, "/api")]
+pub async fn certificate_hash_url() -> Result<Option<String>, ServerFnError> {
+    Ok(use_context::<CertificateHashUrl>().map(|url| url.0))
+}
@@ -1,13 +1,15 @@
 use std::rc::Rc;
 
-use js_sys::Uint8Array;
+use crate::api::{certificate_hash_url, issue_token, ACCESS_TOKEN_PARAM};
+use js_sys::{Array, Object, Reflect, Uint8Array};
 use leptos::{html::Input, *};
 use leptos_use::use_interval_fn;
-use leptos_webtransport::{WebTransportService, WebTransportStatus, WebTransportTask};
+use leptos_webtransport::{WebTransportStatus, WebTransportTask};
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::{spawn_local, JsFuture};
-use web_sys::WebTransport;
-use web_sys::{Event, SubmitEvent};
+use web_sys::WebTransportReceiveStream;
+use web_sys::{Event, ReadableStream, ReadableStreamDefaultReader, Response, SubmitEvent};
+use web_sys::{Url, WebTransport, WebTransportBidirectionalStream, WebTransportOptions};
 
 pub const ECHO_URL: &str = "https://echo.webtransport.rs";
 
@@ -23,6 +25,168 @@ fn is_webtransport_available() -> bool {
     true
 }
 
+/// Whether `url` points at the WebTransport server of the host that served this page.
+fn is_own_server(url: &Url) -> bool {
+    let page_host = window().location().hostname().unwrap_or_default();
+    url.protocol() == "https:" && url.hostname() == page_host
+}
+
+/// Adds a session token to `url` when the server requires one. Tokens are only sent to the
+/// host that served this page, so connecting to another server never leaks one.
+async fn with_token(url: &str) -> String {
+    let Ok(parsed) = Url::new(url) else {
+        return url.to_string();
+    };
+    if !is_own_server(&parsed) {
+        return url.to_string();
+    }
+    match issue_token(parsed.pathname()).await {
+        Ok(Some(token)) => {
+            parsed.search_params().set(ACCESS_TOKEN_PARAM, &token);
+            parsed.href()
+        }
+        Ok(None) => url.to_string(),
+        Err(err) => {
+            logging::error!("Failed to get a session token: {err}");
+            url.to_string()
+        }
+    }
+}
+
+/// Fetches the SHA-256 hash of the self-signed certificate of the server at `url`, or `None`
+/// when the server has a certificate the browser trusts. The certificate is rotated every few
+/// days, so the hash is fetched again for every connection.
+async fn certificate_hash(url: &str) -> Result<Option<Uint8Array>, String> {
+    if !Url::new(url).is_ok_and(|url| is_own_server(&url)) {
+        return Ok(None);
+    }
+    let Some(hash_url) = certificate_hash_url()
+        .await
+        .map_err(|err| err.to_string())?
+    else {
+        return Ok(None);
+    };
+    let response: Response = JsFuture::from(window().fetch_with_str(&hash_url))
+        .await
+        .map_err(|err| format!("{err:?}"))?
+        .unchecked_into();
+    if !response.ok() {
+        return Err(format!("{hash_url} returned {}", response.status()));
+    }
+    let text = response.text().map_err(|err| format!("{err:?}"))?;
+    let hex = JsFuture::from(text)
+        .await
+        .map_err(|err| format!("{err:?}"))?
+        .as_string()
+        .unwrap_or_default();
+    let hash = (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| format!("{hash_url} returned {hex:?}"))?;
+    Ok(Some(Uint8Array::from(hash.as_slice())))
+}
+
+/// An open WebTransport session, with signals carrying what the server sends on it.
+struct Connection {
+    transport: Rc<WebTransport>,
+    status: ReadSignal<WebTransportStatus>,
+    datagram: ReadSignal<Vec<u8>>,
+    unidirectional_stream: ReadSignal<Option<WebTransportReceiveStream>>,
+    bidirectional_stream: ReadSignal<Option<WebTransportBidirectionalStream>>,
+}
+
+/// Calls `on_value` with every value read from `stream` until it ends or fails.
+fn read_all(stream: ReadableStream, on_value: impl Fn(JsValue) + 'static) {
+    let reader = stream
+        .get_reader()
+        .unchecked_into::<ReadableStreamDefaultReader>();
+    spawn_local(async move {
+        while let Ok(result) = JsFuture::from(reader.read()).await {
+            let field = |name: &str| {
+                Reflect::get(&result, &JsValue::from_str(name)).unwrap_or(JsValue::UNDEFINED)
+            };
+            if field("done").is_truthy() {
+                break;
+            }
+            on_value(field("value"));
+        }
+    });
+}
+
+/// Opens a session to `url`. `leptos_webtransport` cannot pass `serverCertificateHashes`, which
+/// a self-signed certificate needs to be accepted without Chrome flags.
+fn connect(url: &str, certificate_hash: Option<Uint8Array>) -> Result<Connection, JsValue> {
+    let options = WebTransportOptions::new();
+    if let Some(value) = certificate_hash {
+        let hash = Object::new();
+        Reflect::set(&hash, &"algorithm".into(), &"sha-256".into())?;
+        Reflect::set(&hash, &"value".into(), &value)?;
+        Reflect::set(
+            &options,
+            &"serverCertificateHashes".into(),
+            &Array::of1(&hash),
+        )?;
+    }
+    let transport = Rc::new(WebTransport::new_with_options(url, &options)?);
+
+    let (status, set_status) = create_signal(WebTransportStatus::Connecting);
+    let ready = JsFuture::from(transport.ready());
+    spawn_local(async move {
+        set_status(match ready.await {
+            Ok(_) => WebTransportStatus::Opened,
+            Err(_) => WebTransportStatus::Error,
+        });
+    });
+    let closed = JsFuture::from(transport.closed());
+    spawn_local(async move {
+        let _ = closed.await;
+        set_status(WebTransportStatus::Closed);
+    });
+
+    let (datagram, set_datagram) = create_signal(Vec::new());
+    read_all(transport.datagrams().readable(), move |value| {
+        set_datagram(value.unchecked_into::<Uint8Array>().to_vec());
+    });
+    let (unidirectional_stream, set_unidirectional_stream) = create_signal(None);
+    read_all(transport.incoming_unidirectional_streams(), move |value| {
+        set_unidirectional_stream(Some(value.unchecked_into()));
+    });
+    let (bidirectional_stream, set_bidirectional_stream) = create_signal(None);
+    read_all(transport.incoming_bidirectional_streams(), move |value| {
+        set_bidirectional_stream(Some(value.unchecked_into()));
+    });
+
+    Ok(Connection {
+        transport,
+        status,
+        datagram,
+        unidirectional_stream,
+        bidirectional_stream,
+    })
+}
+
+/// Describes the `WebTransportCloseInfo` a session was closed with, or the error it ended with.
+fn describe_close(result: Result<JsValue, JsValue>) -> String {
+    let field = |value: &JsValue, name: &str| {
+        js_sys::Reflect::get(value, &JsValue::from_str(name)).unwrap_or(JsValue::UNDEFINED)
+    };
+    match result {
+        Ok(info) => {
+            let code = field(&info, "closeCode").as_f64().unwrap_or_default();
+            let reason = field(&info, "reason").as_string().unwrap_or_default();
+            format!("Session closed with code {code}: {reason:?}")
+        }
+        Err(err) => {
+            let message = field(&err, "message").as_string().unwrap_or_default();
+            format!("Session failed: {message}")
+        }
+    }
+}
+
 #[component]
 pub fn WebtransportDemo() -> impl IntoView {
     let (data, set_data) = create_signal(String::new());
@@ -30,7 +194,8 @@ pub fn WebtransportDemo() -> impl IntoView {
     let url_input_element: NodeRef<Input> = create_node_ref();
     let (connect, set_connect) = create_signal(false);
     let (status, set_status) = create_signal(WebTransportStatus::Closed);
-    let (transport, set_transport) = create_signal::<Option<Rc<WebTransportTask>>>(None);
+    let (close_info, set_close_info) = create_signal::<Option<String>>(None);
+    let (transport, set_transport) = create_signal::<Option<Rc<Connection>>>(None);
     let datagrams = create_rw_signal(create_signal::<Vec<u8>>(Vec::new()).0);
     let unidirectional_streams = create_rw_signal(create_signal::<Option<_>>(None).0);
     let bidirectional_streams = create_rw_signal(create_signal::<Option<_>>(None).0);
@@ -59,21 +224,38 @@ pub fn WebtransportDemo() -> impl IntoView {
             let connected = connect.get_untracked();
 
             if !connected {
-                if let Ok(t) = WebTransportService::connect(&value) {
-                    datagrams.set(t.datagram);
-                    unidirectional_streams.set(t.unidirectional_stream);
-                    bidirectional_streams.set(t.bidirectional_stream);
-                    set_status(t.status.get());
-                    set_transport(Some(Rc::new(t)));
-                }
+                let value = value.clone();
+                spawn_local(async move {
+                    let url = with_token(&value).await;
+                    let certificate_hash = certificate_hash(&url).await.unwrap_or_else(|err| {
+                        logging::error!("Failed to fetch the certificate hash: {err}");
+                        None
+                    });
+                    match connect(&url, certificate_hash) {
+                        Ok(t) => {
+                            set_connect(true);
+                            set_close_info(None);
+                            let closed = JsFuture::from(t.transport.closed());
+                            spawn_local(async move {
+                                set_close_info(Some(describe_close(closed.await)));
+                            });
+                            datagrams.set(t.datagram);
+                            unidirectional_streams.set(t.unidirectional_stream);
+                            bidirectional_streams.set(t.bidirectional_stream);
+                            set_status(t.status.get());
+                            set_transport(Some(Rc::new(t)));
+                        }
+                        Err(err) => logging::error!("Failed to connect: {err:?}"),
+                    }
+                });
             } else {
                 if let Some(t) = transport.get_untracked().as_ref() {
-                    t.close();
+                    t.transport.close();
                 }
                 set_status(WebTransportStatus::Closed);
                 set_transport(None);
+                set_connect(false);
             }
-            set_connect(!connect.get_untracked());
             set_url(value.clone());
         });
     };
@@ -257,6 +439,7 @@ pub fn WebtransportDemo() -> impl IntoView {
                 <h2 class="text-xl font-semibold my-4">
                     {move || { format!("WebTransport Status: {:?}", status.get()) }}
                 </h2>
+                <p class="mb-4">{move || close_info.get()}</p>
                 <form on:submit=send_data class="flex flex-col gap-4">
                     <div class="flex flex-col">
                         <label for="msg_rate" class="mb-2">
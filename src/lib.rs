@@ -1,3 +1,4 @@
+pub mod api;
 pub mod app;
 pub mod components;
 #[cfg(feature = "ssr")]
@@ -5,45 +5,96 @@ async fn main() -> std::io::Result<()> {
     use actix_web::*;
     use leptos::*;
     use leptos_actix::{generate_route_list, LeptosRoutes};
+    use leptos_actix_webtransport_template::api::CertificateHashUrl;
     use leptos_actix_webtransport_template::{app::App, webtransport_server::*};
-    use std::net::ToSocketAddrs;
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::FULL)
-        .with_writer(std::io::stderr)
-        .init();
-
-    let conf = get_configuration(None).await.unwrap();
+    use std::net::Ipv4Addr;
+    let config = Config::load().unwrap_or_else(|err| {
+        eprintln!("error: {err:#}");
+        std::process::exit(2);
+    });
+    if config.help_only() {
+        print!("{}", Config::help_text());
+        return Ok(());
+    }
+    if config.check_only() {
+        print!("{config}");
+    }
+
+    let telemetry_opt = config.telemetry_opt().unwrap_or_else(|err| {
+        eprintln!("invalid configuration: {err:#}");
+        std::process::exit(2);
+    });
+
+    let mut conf = get_configuration(None).await.unwrap();
+    let opt = config
+        .site_addr()
+        .and_then(|site_addr| {
+            if let Some(site_addr) = site_addr {
+                conf.leptos_options.site_addr = site_addr;
+            }
+            config.webtransport_opt(Some(conf.leptos_options.clone()))
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("invalid configuration: {err:#}");
+            std::process::exit(2);
+        });
+    let issue_anonymous_tokens = config.issue_anonymous_tokens().unwrap_or_else(|err| {
+        eprintln!("invalid configuration: {err:#}");
+        std::process::exit(2);
+    });
+    if config.check_only() {
+        if let Err(err) = opt.validate() {
+            eprintln!("invalid configuration: {err:#}");
+            std::process::exit(1);
+        }
+        println!("configuration is valid");
+        return Ok(());
+    }
+
+    // Only after the check above, so checking a configuration never starts an exporter.
+    let telemetry = init_telemetry(&telemetry_opt).unwrap_or_else(|err| {
+        eprintln!("invalid configuration: {err:#}");
+        std::process::exit(2);
+    });
+
     let addr = conf.leptos_options.site_addr;
     // Generate the list of routes in your Leptos App
     let routes = generate_route_list(App);
     println!("listening on http://{}", &addr);
 
-    let opt = WebTransportOpt {
-        listen: std::env::var("LISTEN_URL")
-            .unwrap_or("0.0.0.0:3000".to_string())
-            .to_socket_addrs()
-            .expect("expected LISTEN_URL to be a valid socket address")
-            .next()
-            .expect("expected LISTEN_URL to be a valid socket address"),
-        health_listen: std::env::var("HEALTH_LISTEN_URL")
-            .unwrap_or("0.0.0.0:8080".to_string())
-            .to_socket_addrs()
-            .expect("expected HEALTH_LISTEN_URL to be a valid socket address")
-            .next()
-            .expect("expected HEALTH_LISTEN_URL to be a valid socket address"),
-        certs: Certs {
-            key: std::env::var("KEY_PATH")
-                .unwrap_or("./certs/localhost.key".into())
-                .into(),
-            cert: std::env::var("CERT_PATH")
-                .unwrap_or("./certs/localhost.der".into())
-                .into(),
-        },
-    };
-
-    let _webtransport_server_task = actix_rt::spawn(async move {
-        match start(opt).await {
+    let grace_period = opt.shutdown_grace_period;
+    let alt_svc = opt.alt_svc();
+    println!(
+        "advertising HTTP/3 with Alt-Svc: {}",
+        alt_svc.header_value()
+    );
+
+    let router = Router::new()
+        .route("/", EchoHandler)
+        .route("/echo", EchoHandler);
+
+    let shutdown = Shutdown::new();
+    let signal_shutdown = shutdown.clone();
+    actix_rt::spawn(async move {
+        wait_for_signal().await;
+        println!("shutdown signal received, draining for up to {grace_period:?}");
+        signal_shutdown.trigger();
+    });
+
+    // Without a sign-in, `issue_token` can only hand tokens to anyone, so it is off by default.
+    let token_auth = opt.token_auth.clone().filter(|_| issue_anonymous_tokens);
+    // The hash is served by the health server, which browsers reach on the loopback address
+    // when it listens on every interface.
+    let certificate_hash_url = opt.self_signed.is_some().then(|| {
+        let mut health = opt.health_listen;
+        if health.ip().is_unspecified() {
+            health.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        CertificateHashUrl(format!("http://{health}/certificate-hash"))
+    });
+    let webtransport_shutdown = shutdown.clone();
+    let webtransport_server_task = actix_rt::spawn(async move {
+        match start(opt, router, webtransport_shutdown).await {
             Ok(_) => {}
             Err(e) => {
                 panic!("WebTransport server error: {:?}", e);
@@ -51,12 +102,24 @@ async fn main() -> std::io::Result<()> {
         }
     });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let leptos_options = &conf.leptos_options;
         let site_root = &leptos_options.site_root;
+        let token_auth = token_auth.clone();
+        let certificate_hash_url = certificate_hash_url.clone();
 
         App::new()
-            .route("/api/{tail:.*}", leptos_actix::handle_server_fns())
+            .route(
+                "/api/{tail:.*}",
+                leptos_actix::handle_server_fns_with_context(move || {
+                    if let Some(token_auth) = token_auth.clone() {
+                        provide_context(token_auth);
+                    }
+                    if let Some(url) = certificate_hash_url.clone() {
+                        provide_context(url);
+                    }
+                }),
+            )
             // serve JS/WASM/CSS from `pkg`
             .service(Files::new("/pkg", format!("{site_root}/pkg")))
             // serve other assets from the `assets` directory
@@ -66,10 +129,25 @@ async fn main() -> std::io::Result<()> {
             .leptos_routes(leptos_options.to_owned(), routes.to_owned(), App)
             .app_data(web::Data::new(leptos_options.to_owned()))
             .wrap(middleware::Compress::default())
+            .wrap(alt_svc.middleware())
+            .wrap(tracing_actix_web::TracingLogger::default())
     })
     .bind(&addr)?
-    .run()
-    .await
+    .disable_signals()
+    .shutdown_timeout(grace_period.as_secs())
+    .run();
+
+    let handle = server.handle();
+    actix_rt::spawn(async move {
+        shutdown.wait().await;
+        handle.stop(true).await;
+    });
+
+    server.await?;
+    // wait for the QUIC endpoint to finish draining its sessions
+    let _ = webtransport_server_task.await;
+    telemetry.shutdown().await;
+    Ok(())
 }
 
 #[cfg(feature = "ssr")]
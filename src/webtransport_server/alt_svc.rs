@@ -0,0 +1,44 @@
+use actix_web::middleware::DefaultHeaders;
+use std::time::Duration;
+
+/// Advertises the QUIC endpoint to browsers that reached the site over TCP.
+///
+/// The advertised port defaults to the port the endpoint binds to. Set `external_port` when a
+/// load balancer exposes the endpoint on a different port.
+#[derive(Debug, Clone)]
+pub struct AltSvc {
+    value: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct AltSvcOpt {
+    pub max_age: Duration,
+    pub external_port: Option<u16>,
+}
+
+impl Default for AltSvcOpt {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(86400),
+            external_port: None,
+        }
+    }
+}
+
+impl AltSvc {
+    pub fn new(bind_port: u16, opt: &AltSvcOpt) -> Self {
+        let port = opt.external_port.unwrap_or(bind_port);
+        Self {
+            value: format!("h3=\":{port}\"; ma={}", opt.max_age.as_secs()),
+        }
+    }
+
+    pub fn header_value(&self) -> &str {
+        &self.value
+    }
+
+    /// Adds the `Alt-Svc` header to every response that does not set one itself.
+    pub fn middleware(&self) -> DefaultHeaders {
+        DefaultHeaders::new().add(("Alt-Svc", self.value.as_str()))
+    }
+}
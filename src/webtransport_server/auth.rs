@@ -0,0 +1,318 @@
+use super::router::RequestParams;
+use super::ConnectionConfig;
+use crate::api::ACCESS_TOKEN_PARAM;
+use anyhow::{ensure, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use http::{Request, StatusCode};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+// `{"alg":"HS256","typ":"JWT"}`, the only header tokens are issued or accepted with.
+const HEADER: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9";
+const MIN_SECRET_LEN: usize = 32;
+
+/// Claims of a token that was verified before its session was accepted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    pub aud: String,
+    /// Path of the WebTransport route the token opens a session to.
+    pub path: String,
+    /// Expiry in unix time.
+    pub exp: u64,
+    #[serde(default)]
+    pub iat: u64,
+    /// Random id of the token, logged when it is issued and on the span of the session it
+    /// opens, which ties the two together in traces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+}
+
+/// Issues and verifies HS256 JSON web tokens that authorize WebTransport sessions.
+///
+/// When configured, every CONNECT request must carry a token in the `access_token` query
+/// parameter or an `Authorization: Bearer` header. Requests without a valid, unexpired token
+/// are answered with 401, and tokens for another audience or path with 403.
+pub struct TokenAuth {
+    key: hmac::Key,
+    audience: String,
+    ttl: Duration,
+    rng: SystemRandom,
+}
+
+impl std::fmt::Debug for TokenAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenAuth")
+            .field("audience", &self.audience)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenAuth {
+    pub fn new(secret: &[u8], audience: impl Into<String>, ttl: Duration) -> Result<Self> {
+        ensure!(
+            secret.len() >= MIN_SECRET_LEN,
+            "token secret must be at least {MIN_SECRET_LEN} bytes"
+        );
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            audience: audience.into(),
+            ttl,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Issues a token for a session to `path` that expires after the configured lifetime.
+    pub fn issue(&self, subject: Option<String>, path: &str) -> String {
+        let now = unix_time();
+        let mut id = [0; 12];
+        self.rng
+            .fill(&mut id)
+            .expect("system randomness is available");
+        let claims = Claims {
+            sub: subject,
+            aud: self.audience.clone(),
+            path: path.to_string(),
+            exp: now + self.ttl.as_secs(),
+            iat: now,
+            jti: Some(URL_SAFE_NO_PAD.encode(id)),
+        };
+        debug!(
+            token_id = claims.jti.as_deref(),
+            path, "Issued session token"
+        );
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let message = format!("{HEADER}.{payload}");
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, message.as_bytes()));
+        format!("{message}.{signature}")
+    }
+
+    /// Checks the signature, expiry, audience and path of a token.
+    pub fn verify(&self, token: &str, path: &str) -> Result<Claims, StatusCode> {
+        let mut parts = token.splitn(3, '.');
+        let (Some(header), Some(payload), Some(signature)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(StatusCode::UNAUTHORIZED);
+        };
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let message_len = header.len() + 1 + payload.len();
+        hmac::verify(&self.key, &token.as_bytes()[..message_len], &signature)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        // Only checked once the signature holds, so `alg` cannot be swapped for `none`.
+        let header: serde_json::Value = URL_SAFE_NO_PAD
+            .decode(header)
+            .ok()
+            .and_then(|header| serde_json::from_slice(&header).ok())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        if header["alg"] != "HS256" {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let claims: Claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        if claims.exp <= unix_time() {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        if claims.aud != self.audience || claims.path != path {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(claims)
+    }
+}
+
+/// Verifies the token of a CONNECT request when token auth is configured, and stores its
+/// claims in `params`. Returns the status to reject the request with otherwise.
+pub(crate) fn authorize(
+    config: &ConnectionConfig,
+    req: &Request<()>,
+    params: &mut RequestParams,
+) -> Result<(), StatusCode> {
+    let Some(auth) = &config.token_auth else {
+        return Ok(());
+    };
+    let header = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let token = header.or_else(|| params.query(ACCESS_TOKEN_PARAM));
+    let result = match token {
+        Some(token) => auth.verify(token.trim(), params.path()),
+        None => Err(StatusCode::UNAUTHORIZED),
+    };
+    match result {
+        Ok(claims) => {
+            params.set_claims(claims);
+            Ok(())
+        }
+        Err(status) => {
+            info!(
+                "Rejecting WebTransport session to {} with {status}, token is {}",
+                params.path(),
+                if token.is_some() {
+                    "invalid"
+                } else {
+                    "missing"
+                }
+            );
+            Err(status)
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn auth() -> TokenAuth {
+        TokenAuth::new(SECRET, "demo", Duration::from_secs(60)).unwrap()
+    }
+
+    fn claims(exp: u64) -> Claims {
+        Claims {
+            sub: None,
+            aud: "demo".into(),
+            path: "/echo".into(),
+            exp,
+            iat: 0,
+            jti: None,
+        }
+    }
+
+    fn sign(header: &str, claims: &Claims) -> String {
+        let header = URL_SAFE_NO_PAD.encode(header);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let message = format!("{header}.{payload}");
+        let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET);
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&key, message.as_bytes()));
+        format!("{message}.{signature}")
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let auth = auth();
+        let token = auth.issue(Some("alice".into()), "/echo");
+        let claims = auth.verify(&token, "/echo").unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.exp, claims.iat + 60);
+        assert!(claims.jti.is_some());
+        assert_eq!(
+            URL_SAFE_NO_PAD.decode(HEADER).unwrap(),
+            br#"{"alg":"HS256","typ":"JWT"}"#
+        );
+    }
+
+    #[test]
+    fn rejects_short_secrets() {
+        assert!(TokenAuth::new(&SECRET[1..], "demo", Duration::from_secs(60)).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let auth = auth();
+        let now = unix_time();
+        let header = r#"{"alg":"HS256","typ":"JWT"}"#;
+        assert!(auth
+            .verify(&sign(header, &claims(now + 60)), "/echo")
+            .is_ok());
+        assert_eq!(
+            auth.verify(&sign(header, &claims(now)), "/echo"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            auth.verify(&sign(header, &claims(now - 1)), "/echo"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let auth = auth();
+        let claims = claims(unix_time() + 60);
+        // Correctly signed, but the header does not claim HS256.
+        for header in [
+            r#"{"alg":"none","typ":"JWT"}"#,
+            r#"{"alg":"HS512","typ":"JWT"}"#,
+            r#"{"typ":"JWT"}"#,
+            "not json",
+        ] {
+            assert_eq!(
+                auth.verify(&sign(header, &claims), "/echo"),
+                Err(StatusCode::UNAUTHORIZED),
+                "{header}"
+            );
+        }
+        // An unsigned `alg: none` token.
+        let unsigned = sign(r#"{"alg":"none"}"#, &claims);
+        let unsigned = &unsigned[..unsigned.rfind('.').unwrap() + 1];
+        assert_eq!(
+            auth.verify(unsigned, "/echo"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let auth = auth();
+        let token = auth.issue(None, "/echo");
+        let (message, signature) = token.rsplit_once('.').unwrap();
+        let (header, _) = message.split_once('.').unwrap();
+
+        let mut forged = auth.verify(&token, "/echo").unwrap();
+        forged.path = "/admin".into();
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        let tampered = format!("{header}.{payload}.{signature}");
+        assert_eq!(
+            auth.verify(&tampered, "/admin"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        let mut flipped = token.clone().into_bytes();
+        let last = flipped.len() - 1;
+        flipped[last] = if flipped[last] == b'A' { b'B' } else { b'A' };
+        let flipped = String::from_utf8(flipped).unwrap();
+        assert_eq!(
+            auth.verify(&flipped, "/echo"),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        let other = TokenAuth::new(b"another secret that is long enough", "demo", auth.ttl)
+            .unwrap()
+            .issue(None, "/echo");
+        assert_eq!(auth.verify(&other, "/echo"), Err(StatusCode::UNAUTHORIZED));
+        for malformed in ["", "a.b", "a.b.!!!", message] {
+            assert_eq!(
+                auth.verify(malformed, "/echo"),
+                Err(StatusCode::UNAUTHORIZED)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_audiences_and_paths() {
+        let token = auth().issue(None, "/echo");
+        assert_eq!(auth().verify(&token, "/chat"), Err(StatusCode::FORBIDDEN));
+        let other = TokenAuth::new(SECRET, "other", Duration::from_secs(60)).unwrap();
+        assert_eq!(other.verify(&token, "/echo"), Err(StatusCode::FORBIDDEN));
+    }
+}
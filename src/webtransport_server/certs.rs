@@ -0,0 +1,437 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use rustls::{Certificate, PrivateKey, SignatureScheme};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+// Message signed with the private key to check that it belongs to the leaf certificate.
+const PROBE: &[u8] = b"webtransport.rs key check";
+const PROBE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+/// Where certificate or key material is read from.
+///
+/// Converting from a string treats `env:NAME` as an environment variable, anything containing
+/// a PEM header as inline material and everything else as a path. Environment variables and
+/// inline material hold PEM, or base64 for binary formats such as PKCS#12.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Material {
+    Path(PathBuf),
+    Env(String),
+    Inline(String),
+}
+
+impl From<&str> for Material {
+    fn from(s: &str) -> Self {
+        if let Some(name) = s.strip_prefix("env:") {
+            Self::Env(name.to_string())
+        } else if s.contains("-----BEGIN") {
+            Self::Inline(s.to_string())
+        } else {
+            Self::Path(s.into())
+        }
+    }
+}
+
+impl From<String> for Material {
+    fn from(s: String) -> Self {
+        s.as_str().into()
+    }
+}
+
+impl From<PathBuf> for Material {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+// Inline material may be a private key, so it is never printed.
+impl std::fmt::Debug for Material {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            Self::Env(name) => f.debug_tuple("Env").field(name).finish(),
+            Self::Inline(_) => f.write_str("Inline(..)"),
+        }
+    }
+}
+
+impl std::fmt::Display for Material {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.display()),
+            Self::Env(name) => write!(f, "environment variable {name}"),
+            Self::Inline(_) => f.write_str("inline config"),
+        }
+    }
+}
+
+impl Material {
+    fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::Path(path) => {
+                std::fs::read(path).with_context(|| format!("failed to read {self}"))
+            }
+            Self::Env(name) => {
+                let text = std::env::var(name).with_context(|| format!("{self} is not set"))?;
+                decode_text(&text).with_context(|| format!("invalid material in {self}"))
+            }
+            Self::Inline(text) => {
+                decode_text(text).with_context(|| format!("invalid material in {self}"))
+            }
+        }
+    }
+
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        match self {
+            Self::Path(path) => std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+            Self::Env(_) | Self::Inline(_) => None,
+        }
+    }
+}
+
+/// A certificate chain and its private key.
+#[derive(Clone)]
+pub struct Certs {
+    /// Certificate chain with the leaf first, as PEM, concatenated DER or a PKCS#12 bundle.
+    pub cert: Material,
+    /// PKCS#8 (optionally encrypted), PKCS#1 or SEC1 private key, as PEM or DER. Not needed
+    /// when `cert` is a PKCS#12 bundle, which carries its own key.
+    pub key: Option<Material>,
+    /// Decrypts an encrypted PKCS#8 key or a PKCS#12 bundle.
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for Certs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Certs")
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .field("password", &self.password.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl Certs {
+    /// The most recent modification of the files involved, so a rotation is noticed whichever
+    /// file is replaced last.
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        std::iter::once(&self.cert)
+            .chain(&self.key)
+            .filter_map(Material::modified)
+            .max()
+    }
+}
+
+/// Loads the chain and key, and checks that the key belongs to the leaf certificate.
+pub(crate) fn get_key_and_cert_chain(certs: &Certs) -> Result<(PrivateKey, Vec<Certificate>)> {
+    let password = certs.password.as_deref();
+    let bytes = certs.cert.read()?;
+    let (bundled_key, chain) = if is_pem(&bytes) {
+        let chain = pem_certs(&bytes)
+            .with_context(|| format!("invalid PEM certificate chain in {}", certs.cert))?;
+        (None, chain)
+    } else {
+        match der_chain(&bytes) {
+            Ok(chain) => (None, chain),
+            Err(der_err) => {
+                let (key, chain) = pkcs12(&bytes, password).map_err(|pkcs12_err| {
+                    anyhow!(
+                        "{} is neither a DER certificate chain ({der_err}) nor a PKCS#12 bundle \
+                         ({pkcs12_err})",
+                        certs.cert
+                    )
+                })?;
+                (Some(key), chain)
+            }
+        }
+    };
+    let Some(leaf) = chain.first() else {
+        bail!("no certificates found in {}", certs.cert);
+    };
+    let key = match (bundled_key, &certs.key) {
+        (Some(key), _) => key,
+        (None, Some(source)) => private_key(&source.read()?, password)
+            .with_context(|| format!("failed to load private key from {source}"))?,
+        (None, None) => bail!("no private key configured for {}", certs.cert),
+    };
+    check_key_matches(&key, leaf).with_context(|| {
+        format!(
+            "private key does not belong to the leaf certificate in {}",
+            certs.cert
+        )
+    })?;
+    Ok((key, chain))
+}
+
+fn private_key(bytes: &[u8], password: Option<&str>) -> Result<PrivateKey> {
+    if is_pem(bytes) {
+        for block in pem::parse_many(bytes).context("invalid PEM")? {
+            match block.tag() {
+                "PRIVATE KEY" | "RSA PRIVATE KEY" | "EC PRIVATE KEY" => {
+                    return Ok(PrivateKey(block.into_contents()))
+                }
+                "ENCRYPTED PRIVATE KEY" => return decrypt_pkcs8(block.contents(), password),
+                _ => {}
+            }
+        }
+        bail!(
+            "no private key found, tried PEM blocks PRIVATE KEY (PKCS#8), RSA PRIVATE KEY \
+             (PKCS#1), EC PRIVATE KEY (SEC1) and ENCRYPTED PRIVATE KEY (encrypted PKCS#8)"
+        );
+    }
+    let key = PrivateKey(bytes.to_vec());
+    if rustls::sign::any_supported_type(&key).is_ok() {
+        return Ok(key);
+    }
+    match password {
+        Some(_) => decrypt_pkcs8(bytes, password).context(
+            "DER key is not a PKCS#8, PKCS#1 or SEC1 key, and decrypting it as encrypted \
+             PKCS#8 failed",
+        ),
+        None => bail!(
+            "DER key is not a PKCS#8, PKCS#1 or SEC1 key, set a password if it is encrypted \
+             PKCS#8"
+        ),
+    }
+}
+
+fn decrypt_pkcs8(der: &[u8], password: Option<&str>) -> Result<PrivateKey> {
+    let password = password.context("encrypted PKCS#8 key requires a password")?;
+    let info = pkcs8::EncryptedPrivateKeyInfo::try_from(der)
+        .map_err(|err| anyhow!("invalid encrypted PKCS#8 key: {err}"))?;
+    let document = info
+        .decrypt(password)
+        .map_err(|err| anyhow!("failed to decrypt PKCS#8 key, is the password right? {err}"))?;
+    Ok(PrivateKey(document.as_bytes().to_vec()))
+}
+
+// Bundles often list the leaf after its issuers, so the certificate matching the key is moved
+// to the front.
+fn pkcs12(der: &[u8], password: Option<&str>) -> Result<(PrivateKey, Vec<Certificate>)> {
+    let password = password.unwrap_or("");
+    let pfx = p12::PFX::parse(der).map_err(|err| anyhow!("{err:?}"))?;
+    if !pfx.verify_mac(password) {
+        bail!("MAC verification failed, is the password right?");
+    }
+    let key = pfx
+        .key_bags(password)
+        .map_err(|err| anyhow!("failed to decrypt key: {err:?}"))?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .context("bundle contains no private key")?;
+    let mut chain: Vec<_> = pfx
+        .cert_x509_bags(password)
+        .map_err(|err| anyhow!("failed to decrypt certificates: {err:?}"))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if let Some(leaf) = chain
+        .iter()
+        .position(|cert| check_key_matches(&key, cert).is_ok())
+    {
+        let leaf = chain.remove(leaf);
+        chain.insert(0, leaf);
+    }
+    Ok((key, chain))
+}
+
+fn pem_certs(bytes: &[u8]) -> Result<Vec<Certificate>> {
+    Ok(pem::parse_many(bytes)?
+        .into_iter()
+        .filter(|block| block.tag() == "CERTIFICATE")
+        .map(|block| Certificate(block.into_contents()))
+        .collect())
+}
+
+fn der_chain(mut der: &[u8]) -> Result<Vec<Certificate>> {
+    let mut chain = Vec::new();
+    while !der.is_empty() {
+        let (rest, _) = x509_parser::parse_x509_certificate(der).map_err(|err| anyhow!("{err}"))?;
+        chain.push(Certificate(der[..der.len() - rest.len()].to_vec()));
+        der = rest;
+    }
+    Ok(chain)
+}
+
+fn check_key_matches(key: &PrivateKey, leaf: &Certificate) -> Result<()> {
+    let signing_key =
+        rustls::sign::any_supported_type(key).context("unsupported private key type")?;
+    let signer = signing_key
+        .choose_scheme(PROBE_SCHEMES)
+        .context("private key supports none of the expected signature schemes")?;
+    let algorithm: &'static dyn VerificationAlgorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        SignatureScheme::ED25519 => &signature::ED25519,
+        SignatureScheme::RSA_PSS_SHA256 => &signature::RSA_PSS_2048_8192_SHA256,
+        _ => &signature::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let signature = signer.sign(PROBE)?;
+    let (_, leaf) =
+        x509_parser::parse_x509_certificate(&leaf.0).context("invalid leaf certificate")?;
+    UnparsedPublicKey::new(algorithm, &leaf.public_key().subject_public_key.data)
+        .verify(PROBE, &signature)
+        .map_err(|_| anyhow!("signature made with the key does not verify"))
+}
+
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes.windows(10).any(|window| window == b"-----BEGIN")
+}
+
+fn decode_text(text: &str) -> Result<Vec<u8>> {
+    if text.contains("-----BEGIN") {
+        return Ok(text.as_bytes().to_vec());
+    }
+    let base64: String = text.split_whitespace().collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(base64)
+        .context("expected PEM or base64")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn generate() -> rcgen::Certificate {
+        rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap()
+    }
+
+    fn temp_file(dir: &TempDir, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn material_is_told_apart_by_prefix_and_pem_header() {
+        assert_eq!(
+            Material::from("env:TLS_CERT"),
+            Material::Env("TLS_CERT".into())
+        );
+        assert_eq!(
+            Material::from("certs/a.pem"),
+            Material::Path("certs/a.pem".into())
+        );
+        let inline = "-----BEGIN CERTIFICATE-----\nAA==\n-----END CERTIFICATE-----";
+        assert!(matches!(Material::from(inline), Material::Inline(_)));
+        assert_eq!(format!("{:?}", Material::from(inline)), "Inline(..)");
+    }
+
+    #[test]
+    fn decode_text_accepts_pem_and_wrapped_base64() {
+        assert_eq!(decode_text("aGVs\n bG8=").unwrap(), b"hello");
+        assert!(decode_text("not base64!").is_err());
+        let pem = "-----BEGIN X-----\n-----END X-----";
+        assert_eq!(decode_text(pem).unwrap(), pem.as_bytes());
+    }
+
+    #[test]
+    fn loads_inline_pem_chain_and_key() {
+        let cert = generate();
+        let certs = Certs {
+            cert: cert.serialize_pem().unwrap().into(),
+            key: Some(cert.serialize_private_key_pem().into()),
+            password: None,
+        };
+        let (key, chain) = get_key_and_cert_chain(&certs).unwrap();
+        assert_eq!(key.0, cert.serialize_private_key_der());
+        assert_eq!(chain, vec![Certificate(cert.serialize_der().unwrap())]);
+    }
+
+    #[test]
+    fn loads_der_chain_and_key_from_files() {
+        let dir = TempDir::new().unwrap();
+        let cert = generate();
+        let issuer = generate();
+        let chain_der = [
+            cert.serialize_der().unwrap(),
+            issuer.serialize_der().unwrap(),
+        ]
+        .concat();
+        let certs = Certs {
+            cert: temp_file(&dir, "chain.der", &chain_der).into(),
+            key: Some(temp_file(&dir, "key.der", &cert.serialize_private_key_der()).into()),
+            password: None,
+        };
+        let (_, chain) = get_key_and_cert_chain(&certs).unwrap();
+        assert_eq!(chain.len(), 2);
+    }
+
+    #[test]
+    fn rejects_a_key_of_another_certificate() {
+        let cert = generate();
+        let certs = Certs {
+            cert: cert.serialize_pem().unwrap().into(),
+            key: Some(generate().serialize_private_key_pem().into()),
+            password: None,
+        };
+        let err = get_key_and_cert_chain(&certs).unwrap_err();
+        assert!(format!("{err:#}").contains("does not belong"));
+    }
+
+    #[test]
+    fn pem_keys_are_picked_by_tag() {
+        let sec1 = pem::encode(&pem::Pem::new("EC PRIVATE KEY", vec![1, 2, 3]));
+        let bundle = format!(
+            "{}{sec1}",
+            pem::encode(&pem::Pem::new("CERTIFICATE", vec![0]))
+        );
+        assert_eq!(
+            private_key(bundle.as_bytes(), None).unwrap().0,
+            vec![1, 2, 3]
+        );
+        let no_key = pem::encode(&pem::Pem::new("CERTIFICATE", vec![0]));
+        assert!(private_key(no_key.as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn decrypts_encrypted_pkcs8_keys() {
+        let der = generate().serialize_private_key_der();
+        let encrypted = pkcs8::PrivateKeyInfo::try_from(der.as_slice())
+            .unwrap()
+            .encrypt(rand::rngs::OsRng, "secret")
+            .unwrap();
+        let encrypted = encrypted.as_bytes();
+        assert_eq!(private_key(encrypted, Some("secret")).unwrap().0, der);
+        assert!(private_key(encrypted, Some("wrong")).is_err());
+        assert!(private_key(encrypted, None).is_err());
+
+        let pem = pem::encode(&pem::Pem::new("ENCRYPTED PRIVATE KEY", encrypted.to_vec()));
+        assert_eq!(private_key(pem.as_bytes(), Some("secret")).unwrap().0, der);
+        assert!(private_key(pem.as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn loads_pkcs12_bundles() {
+        let dir = TempDir::new().unwrap();
+        let cert = generate();
+        let cert_der = cert.serialize_der().unwrap();
+        let key_der = cert.serialize_private_key_der();
+        let pfx = p12::PFX::new(&cert_der, &key_der, None, "secret", "leaf").unwrap();
+        let bundle = temp_file(&dir, "bundle.p12", &pfx.to_der());
+
+        let certs = Certs {
+            cert: bundle.clone().into(),
+            key: None,
+            password: Some("secret".into()),
+        };
+        let (key, chain) = get_key_and_cert_chain(&certs).unwrap();
+        assert_eq!(key.0, key_der);
+        assert_eq!(chain, vec![Certificate(cert_der)]);
+
+        let wrong_password = Certs {
+            password: Some("wrong".into()),
+            ..certs
+        };
+        assert!(get_key_and_cert_chain(&wrong_password).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+use super::session::{decode_varint, encode_varint, ConnectRecvStream};
+use super::shutdown::SHUTDOWN_CLOSE_CODE;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use quinn::{ConnectionError, VarInt};
+
+// CLOSE_WEBTRANSPORT_SESSION capsule from draft-ietf-webtrans-http3.
+const CLOSE_WEBTRANSPORT_SESSION: u64 = 0x2843;
+// Longer reasons are not allowed by the draft.
+const MAX_REASON_LEN: usize = 1024;
+// Capsules are small, so anything larger is a misbehaving peer rather than a slow one.
+const MAX_CAPSULE_LEN: usize = 16 * 1024;
+
+/// The session ended without an error, e.g. because the handler was done with it.
+pub const NO_ERROR_CLOSE_CODE: u32 = 0;
+/// `SessionHandler::on_open` returned an error.
+pub const OPEN_FAILED_CLOSE_CODE: u32 = 2;
+
+/// Application error code and reason a session is closed with, which browsers report as the
+/// `WebTransportCloseInfo` of `WebTransport.closed`.
+///
+/// The server only uses the `*_CLOSE_CODE` constants of this module, so handlers are free to
+/// pick any other code for their own reasons.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionClose {
+    pub code: u32,
+    pub reason: String,
+}
+
+impl SessionClose {
+    /// Truncates `reason` to the 1024 bytes the capsule may carry.
+    pub fn new(code: u32, reason: impl Into<String>) -> Self {
+        let mut reason = reason.into();
+        if reason.len() > MAX_REASON_LEN {
+            let mut len = MAX_REASON_LEN;
+            while !reason.is_char_boundary(len) {
+                len -= 1;
+            }
+            reason.truncate(len);
+        }
+        Self { code, reason }
+    }
+
+    pub(crate) fn capsule(&self) -> Bytes {
+        let len = 4 + self.reason.len();
+        let mut buf = BytesMut::with_capacity(8 + len);
+        encode_varint(&mut buf, CLOSE_WEBTRANSPORT_SESSION);
+        encode_varint(&mut buf, len as u64);
+        buf.put_u32(self.code);
+        buf.put_slice(self.reason.as_bytes());
+        buf.freeze()
+    }
+
+    fn from_capsule(mut payload: Bytes) -> Option<Self> {
+        if payload.remaining() < 4 {
+            return None;
+        }
+        let code = payload.get_u32();
+        Some(Self::new(code, String::from_utf8_lossy(&payload)))
+    }
+}
+
+impl std::fmt::Display for SessionClose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "code {}", self.code)?;
+        if !self.reason.is_empty() {
+            write!(f, " ({})", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a session ended, as passed to `SessionHandler::on_close`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The server closed the session, through `SessionContext::close` or with one of the
+    /// `*_CLOSE_CODE` constants, or closed the connection carrying it.
+    ClosedLocally(SessionClose),
+    /// The peer closed the session with a CLOSE_WEBTRANSPORT_SESSION capsule or a FIN on its
+    /// CONNECT stream, or closed the connection without an error.
+    Closed(SessionClose),
+    /// The peer reset the CONNECT stream, or closed the connection, with this error code.
+    Reset(u64),
+    /// The connection failed underneath the session, e.g. because the peer went silent.
+    TransportError(String),
+}
+
+impl SessionEnd {
+    pub(crate) fn from_connection(err: &ConnectionError) -> Self {
+        match err {
+            ConnectionError::ApplicationClosed(close)
+                if close.error_code == SHUTDOWN_CLOSE_CODE
+                    || close.error_code == VarInt::from_u32(0) =>
+            {
+                Self::Closed(SessionClose::new(
+                    NO_ERROR_CLOSE_CODE,
+                    String::from_utf8_lossy(&close.reason),
+                ))
+            }
+            ConnectionError::ApplicationClosed(close) => Self::Reset(close.error_code.into_inner()),
+            ConnectionError::LocallyClosed => Self::ClosedLocally(SessionClose::new(
+                NO_ERROR_CLOSE_CODE,
+                "connection closed by the server",
+            )),
+            err => Self::TransportError(err.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for SessionEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ClosedLocally(close) => write!(f, "closed by the server with {close}"),
+            Self::Closed(close) => write!(f, "closed by the peer with {close}"),
+            Self::Reset(code) => write!(f, "reset by the peer with code {code:#x}"),
+            Self::TransportError(err) => write!(f, "transport error: {err}"),
+        }
+    }
+}
+
+/// Reads the CONNECT stream of a session until the peer ends it, and reports how.
+///
+/// Data on the stream is a sequence of capsules. Only CLOSE_WEBTRANSPORT_SESSION is acted
+/// upon, everything else is skipped.
+pub(crate) async fn watch_connect_stream(
+    mut stream: ConnectRecvStream,
+    conn: quinn::Connection,
+) -> SessionEnd {
+    let mut buf = BytesMut::new();
+    loop {
+        match stream.recv_data().await {
+            Ok(Some(data)) => buf.put(data),
+            Ok(None) => return SessionEnd::Closed(SessionClose::new(NO_ERROR_CLOSE_CODE, "")),
+            Err(err) => {
+                if let Some(reason) = conn.close_reason() {
+                    return SessionEnd::from_connection(&reason);
+                }
+                return match err.try_get_code() {
+                    Some(code) => SessionEnd::Reset(code.value()),
+                    None => SessionEnd::TransportError(err.to_string()),
+                };
+            }
+        }
+        loop {
+            match next_capsule(&mut buf) {
+                Ok(Some((CLOSE_WEBTRANSPORT_SESSION, payload))) => {
+                    let close = SessionClose::from_capsule(payload)
+                        .unwrap_or_else(|| SessionClose::new(NO_ERROR_CLOSE_CODE, ""));
+                    return SessionEnd::Closed(close);
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(end) => return end,
+            }
+        }
+    }
+}
+
+// Takes the next complete capsule off `buf`, leaving a partial one in place.
+fn next_capsule(buf: &mut BytesMut) -> Result<Option<(u64, Bytes)>, SessionEnd> {
+    let mut peek = &buf[..];
+    let (Some(kind), Some(len)) = (decode_varint(&mut peek), decode_varint(&mut peek)) else {
+        return Ok(None);
+    };
+    if len > MAX_CAPSULE_LEN as u64 {
+        return Err(SessionEnd::TransportError(format!(
+            "capsule of {len} bytes on the CONNECT stream"
+        )));
+    }
+    if peek.len() < len as usize {
+        return Ok(None);
+    }
+    let header_len = buf.len() - peek.len();
+    buf.advance(header_len);
+    Ok(Some((kind, buf.split_to(len as usize).freeze())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_capsule_bytes() {
+        let capsule = SessionClose::new(7, "bye").capsule();
+        assert_eq!(
+            &capsule[..],
+            // Type 0x2843 and length 7 as varints, then the code and the reason.
+            [0x68, 0x43, 0x07, 0, 0, 0, 7, b'b', b'y', b'e']
+        );
+        let empty = SessionClose::new(u32::MAX, "").capsule();
+        assert_eq!(&empty[..], [0x68, 0x43, 0x04, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn close_capsules_round_trip() {
+        let close = SessionClose::new(42, "going away");
+        let mut buf = BytesMut::from(&close.capsule()[..]);
+        let (kind, payload) = next_capsule(&mut buf).unwrap().unwrap();
+        assert_eq!(kind, CLOSE_WEBTRANSPORT_SESSION);
+        assert_eq!(SessionClose::from_capsule(payload), Some(close));
+        assert!(buf.is_empty());
+        assert_eq!(
+            SessionClose::from_capsule(Bytes::from_static(&[0, 0, 1])),
+            None
+        );
+    }
+
+    #[test]
+    fn reasons_are_truncated_on_a_char_boundary() {
+        let close = SessionClose::new(0, "é".repeat(MAX_REASON_LEN));
+        assert_eq!(close.reason.len(), MAX_REASON_LEN);
+        let close = SessionClose::new(0, format!("x{}", "é".repeat(MAX_REASON_LEN)));
+        assert_eq!(close.reason.len(), MAX_REASON_LEN - 1);
+        let len = close.capsule().len();
+        assert_eq!(len, 2 + 2 + 4 + MAX_REASON_LEN - 1);
+    }
+
+    #[test]
+    fn truncated_capsules_wait_for_more_data() {
+        let capsule = SessionClose::new(7, "bye").capsule();
+        for cut in 0..capsule.len() {
+            let mut buf = BytesMut::from(&capsule[..cut]);
+            assert_eq!(next_capsule(&mut buf), Ok(None), "cut at {cut}");
+            assert_eq!(buf.len(), cut, "a partial capsule is left in place");
+            buf.extend_from_slice(&capsule[cut..]);
+            assert!(next_capsule(&mut buf).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn capsules_are_taken_one_at_a_time() {
+        let mut buf = BytesMut::new();
+        encode_varint(&mut buf, 0x1234);
+        encode_varint(&mut buf, 2);
+        buf.put_slice(b"hi");
+        buf.put(SessionClose::new(1, "").capsule());
+        buf.put_u8(0x68);
+
+        assert_eq!(
+            next_capsule(&mut buf),
+            Ok(Some((0x1234, Bytes::from_static(b"hi"))))
+        );
+        let (kind, _) = next_capsule(&mut buf).unwrap().unwrap();
+        assert_eq!(kind, CLOSE_WEBTRANSPORT_SESSION);
+        assert_eq!(next_capsule(&mut buf), Ok(None));
+        assert_eq!(&buf[..], [0x68]);
+    }
+
+    #[test]
+    fn oversized_capsules_end_the_session() {
+        let mut buf = BytesMut::new();
+        encode_varint(&mut buf, CLOSE_WEBTRANSPORT_SESSION);
+        encode_varint(&mut buf, MAX_CAPSULE_LEN as u64 + 1);
+        assert!(matches!(
+            next_capsule(&mut buf),
+            Err(SessionEnd::TransportError(_))
+        ));
+    }
+
+    #[test]
+    fn varints_use_the_shortest_encoding() {
+        for (value, len) in [
+            (0, 1),
+            (63, 1),
+            (64, 2),
+            (16383, 2),
+            (16384, 4),
+            ((1 << 30) - 1, 4),
+            (1 << 30, 8),
+            ((1 << 62) - 1, 8),
+        ] {
+            let mut buf = BytesMut::new();
+            encode_varint(&mut buf, value);
+            assert_eq!(buf.len(), len, "{value}");
+            let mut encoded = buf.freeze();
+            assert_eq!(decode_varint(&mut encoded), Some(value));
+            assert!(encoded.is_empty());
+        }
+    }
+
+    #[test]
+    fn truncated_varints_are_not_decoded() {
+        let mut buf = BytesMut::new();
+        encode_varint(&mut buf, 1 << 30);
+        for cut in 0..buf.len() {
+            let mut truncated = &buf[..cut];
+            assert_eq!(decode_varint(&mut truncated), None);
+            assert_eq!(truncated.len(), cut, "nothing is consumed");
+        }
+    }
+
+    #[test]
+    fn connection_closes_map_to_session_ends() {
+        let closed = |code: u32| {
+            ConnectionError::ApplicationClosed(quinn::ApplicationClose {
+                error_code: VarInt::from_u32(code),
+                reason: Bytes::from_static(b"bye"),
+            })
+        };
+        assert_eq!(
+            SessionEnd::from_connection(&closed(0x100)),
+            SessionEnd::Closed(SessionClose::new(NO_ERROR_CLOSE_CODE, "bye"))
+        );
+        assert_eq!(
+            SessionEnd::from_connection(&closed(0x107)),
+            SessionEnd::Reset(0x107)
+        );
+        assert!(matches!(
+            SessionEnd::from_connection(&ConnectionError::LocallyClosed),
+            SessionEnd::ClosedLocally(_)
+        ));
+        assert!(matches!(
+            SessionEnd::from_connection(&ConnectionError::TimedOut),
+            SessionEnd::TransportError(_)
+        ));
+    }
+}
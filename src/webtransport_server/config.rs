@@ -0,0 +1,872 @@
+use super::{
+    AltSvcOpt, Certs, ClientAuthOpt, RateLimitOpt, SelfSignedOpt, SniCert, TelemetryOpt, TokenAuth,
+    TransportOpt, WebTransportOpt,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use leptos::LeptosOptions;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A server option, named `key` in the config file (sections are dotted), `--key` on the
+/// command line with dots and underscores written as dashes, and `env` in the environment.
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    default: Option<&'static str>,
+    help: &'static str,
+}
+
+const fn setting(
+    key: &'static str,
+    env: &'static str,
+    default: Option<&'static str>,
+    help: &'static str,
+) -> Setting {
+    Setting {
+        key,
+        env,
+        default,
+        help,
+    }
+}
+
+// Values are never printed for these.
+const SECRETS: &[&str] = &["tls.key_password", "auth.secret"];
+
+const SETTINGS: &[Setting] = &[
+    setting(
+        "listen",
+        "LISTEN_URL",
+        Some("0.0.0.0:3000"),
+        "QUIC endpoint address",
+    ),
+    setting(
+        "health_listen",
+        "HEALTH_LISTEN_URL",
+        Some("0.0.0.0:8080"),
+        "health server address",
+    ),
+    setting(
+        "site_addr",
+        "SITE_ADDR",
+        None,
+        "HTTP server address, defaults to the Leptos site address",
+    ),
+    setting(
+        "max_sessions_per_connection",
+        "MAX_SESSIONS_PER_CONNECTION",
+        Some("16"),
+        "WebTransport sessions per QUIC connection",
+    ),
+    setting(
+        "shutdown_grace_period",
+        "SHUTDOWN_GRACE_PERIOD",
+        Some("20"),
+        "seconds sessions get to finish on shutdown",
+    ),
+    setting(
+        "allowed_origins",
+        "ALLOWED_ORIGINS",
+        None,
+        "origins allowed to open sessions separated by ;, such as https://*.example.com",
+    ),
+    setting(
+        "auth.secret",
+        "AUTH_SECRET",
+        None,
+        "HMAC key for session tokens, enables token auth",
+    ),
+    setting(
+        "auth.audience",
+        "AUTH_AUDIENCE",
+        Some("webtransport"),
+        "audience of session tokens",
+    ),
+    setting(
+        "auth.token_ttl",
+        "AUTH_TOKEN_TTL",
+        Some("60"),
+        "seconds issued session tokens stay valid",
+    ),
+    setting(
+        "auth.issue_anonymous",
+        "AUTH_ISSUE_ANONYMOUS",
+        Some("false"),
+        "demo only: issue session tokens to every visitor of the site",
+    ),
+    setting(
+        "limits.datagrams_per_sec",
+        "LIMIT_DATAGRAMS_PER_SEC",
+        None,
+        "incoming datagrams per second per session",
+    ),
+    setting(
+        "limits.bytes_per_sec",
+        "LIMIT_BYTES_PER_SEC",
+        None,
+        "incoming datagram and stream bytes per second per session",
+    ),
+    setting(
+        "limits.streams_per_sec",
+        "LIMIT_STREAMS_PER_SEC",
+        None,
+        "new incoming streams per second per session",
+    ),
+    setting(
+        "limits.connections_per_ip",
+        "LIMIT_CONNECTIONS_PER_IP",
+        None,
+        "open QUIC connections per source IP",
+    ),
+    setting(
+        "limits.handshakes_per_ip",
+        "LIMIT_HANDSHAKES_PER_IP",
+        None,
+        "QUIC handshakes in progress per source IP",
+    ),
+    setting(
+        "limits.action",
+        "LIMIT_ACTION",
+        Some("drop"),
+        "excess streams: drop, or reset with code 429",
+    ),
+    setting(
+        "limits.max_stream_bytes",
+        "LIMIT_MAX_STREAM_BYTES",
+        None,
+        "bytes a single incoming stream may carry",
+    ),
+    setting(
+        "limits.max_session_bytes",
+        "LIMIT_MAX_SESSION_BYTES",
+        None,
+        "stream bytes a session may send in total",
+    ),
+    setting(
+        "limits.max_datagram_size",
+        "LIMIT_MAX_DATAGRAM_SIZE",
+        None,
+        "largest incoming datagram payload passed to handlers",
+    ),
+    setting(
+        "readiness_check_path",
+        "READINESS_CHECK_PATH",
+        None,
+        "echo route /readyz sends a loopback datagram through",
+    ),
+    setting(
+        "tls.cert",
+        "CERT_PATH",
+        Some("./certs/localhost.der"),
+        "certificate chain: path, env:NAME or inline PEM",
+    ),
+    setting(
+        "tls.key",
+        "KEY_PATH",
+        Some("./certs/localhost.key"),
+        "private key: path, env:NAME or inline PEM",
+    ),
+    setting(
+        "tls.key_password",
+        "KEY_PASSWORD",
+        None,
+        "password of an encrypted key or PKCS#12 bundle",
+    ),
+    setting(
+        "tls.self_signed",
+        "SELF_SIGNED_CERT",
+        Some("false"),
+        "serve a generated certificate instead of tls.cert",
+    ),
+    setting(
+        "tls.sni_certs",
+        "SNI_CERTS",
+        None,
+        "server_name=cert,key entries separated by ;",
+    ),
+    setting(
+        "tls.reload_interval",
+        "CERT_RELOAD_INTERVAL",
+        Some("60"),
+        "seconds between certificate change checks",
+    ),
+    setting(
+        "tls.client_auth",
+        "CLIENT_AUTH",
+        Some("off"),
+        "client certificates: off, optional or required",
+    ),
+    setting(
+        "tls.client_ca",
+        "CLIENT_CA_PATH",
+        None,
+        "CA bundle for client certificates",
+    ),
+    setting(
+        "tls.zero_rtt",
+        "ZERO_RTT",
+        Some("false"),
+        "accept 0-RTT early data from resumed TLS sessions",
+    ),
+    setting(
+        "alt_svc.max_age",
+        "ALT_SVC_MAX_AGE",
+        Some("86400"),
+        "seconds browsers remember the QUIC endpoint",
+    ),
+    setting(
+        "alt_svc.port",
+        "ALT_SVC_PORT",
+        None,
+        "advertised QUIC port, defaults to the listen port",
+    ),
+    setting(
+        "quic.max_bidi_streams",
+        "QUIC_MAX_BIDI_STREAMS",
+        None,
+        "concurrent bidirectional streams per connection",
+    ),
+    setting(
+        "quic.max_uni_streams",
+        "QUIC_MAX_UNI_STREAMS",
+        None,
+        "concurrent unidirectional streams per connection",
+    ),
+    setting(
+        "quic.stream_receive_window",
+        "QUIC_STREAM_RECEIVE_WINDOW",
+        None,
+        "receive window per stream in bytes",
+    ),
+    setting(
+        "quic.receive_window",
+        "QUIC_RECEIVE_WINDOW",
+        None,
+        "receive window per connection in bytes",
+    ),
+    setting(
+        "quic.send_window",
+        "QUIC_SEND_WINDOW",
+        None,
+        "send window per connection in bytes",
+    ),
+    setting(
+        "quic.datagram_receive_buffer",
+        "QUIC_DATAGRAM_RECEIVE_BUFFER",
+        None,
+        "incoming datagram buffer in bytes",
+    ),
+    setting(
+        "quic.datagram_send_buffer",
+        "QUIC_DATAGRAM_SEND_BUFFER",
+        None,
+        "outgoing datagram buffer in bytes",
+    ),
+    setting(
+        "quic.initial_rtt_ms",
+        "QUIC_INITIAL_RTT_MS",
+        None,
+        "RTT assumed before the first sample",
+    ),
+    setting(
+        "quic.mtu_discovery",
+        "QUIC_MTU_DISCOVERY",
+        Some("true"),
+        "probe for a larger MTU",
+    ),
+    setting(
+        "quic.keep_alive_interval",
+        "QUIC_KEEP_ALIVE_INTERVAL",
+        Some("2"),
+        "seconds between keep-alive packets",
+    ),
+    setting(
+        "quic.idle_timeout_ms",
+        "QUIC_IDLE_TIMEOUT_MS",
+        Some("10000"),
+        "idle time before a connection is closed",
+    ),
+    setting(
+        "quic.congestion_controller",
+        "QUIC_CONGESTION_CONTROLLER",
+        Some("cubic"),
+        "newreno, cubic or bbr",
+    ),
+    setting(
+        "debug.key_log_file",
+        "SSLKEYLOGFILE",
+        None,
+        "append TLS secrets here to decrypt captures, never in production",
+    ),
+    setting(
+        "debug.qlog_dir",
+        "QLOG_DIR",
+        None,
+        "directory for per-connection qlog event logs",
+    ),
+    setting(
+        "log.format",
+        "LOG_FORMAT",
+        Some("text"),
+        "log lines on stderr: text or json",
+    ),
+    setting(
+        "otlp.endpoint",
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        None,
+        "OTLP/gRPC collector to export traces to",
+    ),
+    setting(
+        "otlp.service_name",
+        "OTEL_SERVICE_NAME",
+        Some("leptos-webtransport"),
+        "service.name of exported traces",
+    ),
+];
+
+/// Where a configuration value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(&'static str),
+    Cli(String),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => f.write_str("the default"),
+            Self::File(path) => write!(f, "config file {}", path.display()),
+            Self::Env(name) => write!(f, "environment variable {name}"),
+            Self::Cli(flag) => write!(f, "command-line flag {flag}"),
+        }
+    }
+}
+
+/// Server configuration resolved from, in increasing priority, defaults, a TOML file given with
+/// `--config` or `CONFIG_FILE`, environment variables and command-line flags.
+pub struct Config {
+    values: BTreeMap<&'static str, (String, Source)>,
+    check: bool,
+    help: bool,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Self::resolve(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    fn resolve(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let mut config = Self {
+            values: BTreeMap::new(),
+            check: false,
+            help: false,
+        };
+        for setting in SETTINGS {
+            if let Some(default) = setting.default {
+                config.set(setting.key, default.to_string(), Source::Default);
+            }
+        }
+
+        let cli = parse_args(args)?;
+        config.check = cli.check;
+        config.help = cli.help;
+        let file = cli
+            .config_file
+            .or_else(|| env("CONFIG_FILE").map(PathBuf::from));
+        if let Some(path) = file {
+            config.load_file(path)?;
+        }
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.env) {
+                config.set(setting.key, value, Source::Env(setting.env));
+            }
+        }
+        for (key, value, flag) in cli.values {
+            config.set(key, value, Source::Cli(flag));
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &'static str, value: String, source: Source) {
+        self.values.insert(key, (value, source));
+    }
+
+    fn load_file(&mut self, path: PathBuf) -> Result<()> {
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let table: toml::Table = text
+            .parse()
+            .with_context(|| format!("invalid TOML in config file {}", path.display()))?;
+        let mut values = Vec::new();
+        flatten("", &table, &mut values)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        for (key, value) in values {
+            let setting = find(&key).with_context(|| {
+                format!("unknown option `{key}` in config file {}", path.display())
+            })?;
+            self.set(setting.key, value, Source::File(path.clone()));
+        }
+        Ok(())
+    }
+
+    /// Whether `--check-config` was passed.
+    pub fn check_only(&self) -> bool {
+        self.check
+    }
+
+    /// Whether `--help` was passed.
+    pub fn help_only(&self) -> bool {
+        self.help
+    }
+
+    pub fn help_text() -> String {
+        let mut text =
+            String::from("Options, also settable in a TOML file passed with --config <file>:\n\n");
+        for setting in SETTINGS {
+            text.push_str(&format!(
+                "  --{:<32} {:<30} {}{}\n",
+                flag_name(setting.key),
+                setting.env,
+                setting.help,
+                setting
+                    .default
+                    .map_or(String::new(), |default| format!(" [default: {default}]"))
+            ));
+        }
+        text.push_str(
+            "\n  --check-config                   validate and print the configuration\n",
+        );
+        text
+    }
+
+    fn raw(&self, key: &str) -> Option<&(String, Source)> {
+        debug_assert!(find(key).is_some(), "undeclared setting {key}");
+        self.values.get(key)
+    }
+
+    fn parse_with<T, E: Display>(
+        &self,
+        key: &str,
+        parse: impl FnOnce(&str) -> Result<T, E>,
+    ) -> Result<Option<T>> {
+        let Some((value, source)) = self.raw(key) else {
+            return Ok(None);
+        };
+        parse(value.trim())
+            .map(Some)
+            .map_err(|err| anyhow!("invalid value {value:?} for `{key}` from {source}: {err}"))
+    }
+
+    fn get<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.parse_with(key, str::parse)
+    }
+
+    fn require<T>(&self, key: &str) -> Result<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.get(key)?
+            .with_context(|| format!("missing value for `{key}`"))
+    }
+
+    fn secs(&self, key: &str) -> Result<Option<Duration>> {
+        Ok(self.get(key)?.map(Duration::from_secs))
+    }
+
+    fn millis(&self, key: &str) -> Result<Option<Duration>> {
+        Ok(self.get(key)?.map(Duration::from_millis))
+    }
+
+    fn socket_addr(&self, key: &str) -> Result<Option<SocketAddr>> {
+        self.parse_with(key, |value| {
+            value
+                .to_socket_addrs()
+                .map_err(|err| err.to_string())?
+                .next()
+                .ok_or_else(|| "no addresses found".to_string())
+        })
+    }
+
+    fn list<T>(&self, key: &str) -> Result<Vec<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        Ok(self
+            .parse_with(key, |value| {
+                value
+                    .split(';')
+                    .filter(|entry| !entry.trim().is_empty())
+                    .map(|entry| entry.trim().parse())
+                    .collect::<Result<Vec<T>, _>>()
+            })?
+            .unwrap_or_default())
+    }
+
+    /// Address for the actix server, when it overrides the Leptos site address.
+    pub fn site_addr(&self) -> Result<Option<SocketAddr>> {
+        self.socket_addr("site_addr")
+    }
+
+    /// Whether the site's `issue_token` server function hands session tokens to anyone who
+    /// asks. Only meant for the demo, which has no sign-in.
+    pub fn issue_anonymous_tokens(&self) -> Result<bool> {
+        self.require("auth.issue_anonymous")
+    }
+
+    pub fn telemetry_opt(&self) -> Result<TelemetryOpt> {
+        Ok(TelemetryOpt {
+            log_format: self.require("log.format")?,
+            otlp_endpoint: self.get("otlp.endpoint")?,
+            service_name: self.require("otlp.service_name")?,
+        })
+    }
+
+    pub fn webtransport_opt(&self, site: Option<LeptosOptions>) -> Result<WebTransportOpt> {
+        Ok(WebTransportOpt {
+            listen: self
+                .socket_addr("listen")?
+                .context("missing value for `listen`")?,
+            health_listen: self
+                .socket_addr("health_listen")?
+                .context("missing value for `health_listen`")?,
+            certs: Certs {
+                cert: self.require::<String>("tls.cert")?.into(),
+                key: self.get::<String>("tls.key")?.map(Into::into),
+                password: self.get("tls.key_password")?,
+            },
+            self_signed: self
+                .require::<bool>("tls.self_signed")?
+                .then(SelfSignedOpt::default),
+            sni_certs: self.list("tls.sni_certs")?,
+            client_auth: ClientAuthOpt {
+                mode: self.require("tls.client_auth")?,
+                ca_certs: self.get::<PathBuf>("tls.client_ca")?,
+            },
+            zero_rtt: self.require("tls.zero_rtt")?,
+            allowed_origins: self.list("allowed_origins")?,
+            token_auth: self
+                .get::<String>("auth.secret")?
+                .map(|secret| {
+                    TokenAuth::new(
+                        secret.as_bytes(),
+                        self.require::<String>("auth.audience")?,
+                        self.secs("auth.token_ttl")?
+                            .context("missing value for `auth.token_ttl`")?,
+                    )
+                    .map(Arc::new)
+                })
+                .transpose()
+                .context("invalid token auth settings")?,
+            cert_reload_interval: self.secs("tls.reload_interval")?,
+            max_sessions_per_connection: self.require("max_sessions_per_connection")?,
+            transport: TransportOpt {
+                max_concurrent_bidi_streams: self.get("quic.max_bidi_streams")?,
+                max_concurrent_uni_streams: self.get("quic.max_uni_streams")?,
+                stream_receive_window: self.get("quic.stream_receive_window")?,
+                receive_window: self.get("quic.receive_window")?,
+                send_window: self.get("quic.send_window")?,
+                datagram_receive_buffer_size: self.get("quic.datagram_receive_buffer")?,
+                datagram_send_buffer_size: self.get("quic.datagram_send_buffer")?,
+                initial_rtt: self.millis("quic.initial_rtt_ms")?,
+                mtu_discovery: self.require("quic.mtu_discovery")?,
+                keep_alive_interval: self.secs("quic.keep_alive_interval")?,
+                max_idle_timeout: self
+                    .millis("quic.idle_timeout_ms")?
+                    .context("missing value for `quic.idle_timeout_ms`")?,
+                congestion_controller: self.require("quic.congestion_controller")?,
+            },
+            rate_limits: RateLimitOpt {
+                datagrams_per_sec: self.get("limits.datagrams_per_sec")?,
+                bytes_per_sec: self.get("limits.bytes_per_sec")?,
+                streams_per_sec: self.get("limits.streams_per_sec")?,
+                max_connections_per_ip: self.get("limits.connections_per_ip")?,
+                max_handshakes_per_ip: self.get("limits.handshakes_per_ip")?,
+                action: self.require("limits.action")?,
+                max_stream_bytes: self.get("limits.max_stream_bytes")?,
+                max_session_bytes: self.get("limits.max_session_bytes")?,
+                max_datagram_size: self.get("limits.max_datagram_size")?,
+            },
+            site,
+            alt_svc: AltSvcOpt {
+                max_age: self
+                    .secs("alt_svc.max_age")?
+                    .context("missing value for `alt_svc.max_age`")?,
+                external_port: self.get("alt_svc.port")?,
+            },
+            shutdown_grace_period: self
+                .secs("shutdown_grace_period")?
+                .context("missing value for `shutdown_grace_period`")?,
+            readiness_check_path: self.get("readiness_check_path")?,
+            key_log_file: self.get("debug.key_log_file")?,
+            qlog_dir: self.get("debug.qlog_dir")?,
+        })
+    }
+}
+
+/// Prints every value that is set, with the source it came from.
+impl Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, (value, source)) in &self.values {
+            let value = if SECRETS.contains(key) || value.contains("-----BEGIN") {
+                "<redacted>"
+            } else {
+                value
+            };
+            writeln!(f, "{key} = {value:?}  # {source}")?;
+        }
+        Ok(())
+    }
+}
+
+fn find(key: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.key == key)
+}
+
+fn flag_name(key: &str) -> String {
+    key.replace(['.', '_'], "-")
+}
+
+#[derive(Default)]
+struct Args {
+    config_file: Option<PathBuf>,
+    values: Vec<(&'static str, String, String)>,
+    check: bool,
+    help: bool,
+}
+
+// Accepts `--flag value` and `--flag=value`.
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            bail!("unexpected argument {arg:?}, options start with --");
+        };
+        let (name, mut inline) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+        let mut value = || match inline.take() {
+            Some(value) => Ok(value),
+            None => args
+                .next()
+                .with_context(|| format!("missing value for --{name}")),
+        };
+        match name {
+            "check-config" => parsed.check = true,
+            "help" => parsed.help = true,
+            "config" => parsed.config_file = Some(value()?.into()),
+            _ => {
+                let setting = SETTINGS
+                    .iter()
+                    .find(|setting| flag_name(setting.key) == name)
+                    .with_context(|| format!("unknown option --{name}, see --help"))?;
+                parsed
+                    .values
+                    .push((setting.key, value()?, format!("--{name}")));
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+// Turns nested tables into dotted keys. Arrays become `;`-separated lists.
+fn flatten(prefix: &str, table: &toml::Table, values: &mut Vec<(String, String)>) -> Result<()> {
+    for (name, value) in table {
+        let key = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{prefix}.{name}")
+        };
+        match value {
+            toml::Value::Table(table) => flatten(&key, table, values)?,
+            toml::Value::Array(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| scalar(&key, item))
+                    .collect::<Result<Vec<_>>>()?;
+                values.push((key, items.join(";")));
+            }
+            value => {
+                let value = scalar(&key, value)?;
+                values.push((key, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn scalar(key: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => bail!("`{key}` must be a string, number or boolean"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn config_file(dir: &TempDir, name: &str, contents: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_both_flag_forms() {
+        let cli = parse_args(args(&[
+            "--listen=127.0.0.1:4433",
+            "--max-sessions-per-connection",
+            "4",
+            "--check-config",
+        ]))
+        .unwrap();
+        assert!(cli.check);
+        assert_eq!(
+            cli.values,
+            vec![
+                ("listen", "127.0.0.1:4433".into(), "--listen".into()),
+                (
+                    "max_sessions_per_connection",
+                    "4".into(),
+                    "--max-sessions-per-connection".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse_args(args(&["listen"])).is_err());
+        assert!(parse_args(args(&["--no-such-option=1"])).is_err());
+        assert!(parse_args(args(&["--listen"])).is_err());
+    }
+
+    #[test]
+    fn flattens_sections_and_arrays() {
+        let table: toml::Table = r#"
+            listen = "0.0.0.0:4433"
+            allowed_origins = ["https://a.example", "https://b.example"]
+            [limits]
+            datagrams_per_sec = 2.5
+            max_stream_bytes = 1024
+            [tls]
+            zero_rtt = true
+        "#
+        .parse()
+        .unwrap();
+        let mut values = Vec::new();
+        flatten("", &table, &mut values).unwrap();
+        values.sort();
+        assert_eq!(
+            values,
+            [
+                ("allowed_origins", "https://a.example;https://b.example"),
+                ("limits.datagrams_per_sec", "2.5"),
+                ("limits.max_stream_bytes", "1024"),
+                ("listen", "0.0.0.0:4433"),
+                ("tls.zero_rtt", "true"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+
+        let nested: toml::Table = "allowed_origins = [[\"a\"]]".parse().unwrap();
+        assert!(flatten("", &nested, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn cli_overrides_env_which_overrides_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = config_file(
+            &dir,
+            "layers.toml",
+            "listen = \"127.0.0.1:1\"\nmax_sessions_per_connection = 1\n\
+             [limits]\nmax_stream_bytes = 1\n",
+        );
+        let env = |name: &str| match name {
+            "CONFIG_FILE" => Some(path.display().to_string()),
+            "MAX_SESSIONS_PER_CONNECTION" => Some("2".into()),
+            "LIMIT_MAX_STREAM_BYTES" => Some("2".into()),
+            _ => None,
+        };
+        let config = Config::resolve(args(&["--limits-max-stream-bytes", "3"]), env).unwrap();
+
+        let (value, source) = config.raw("listen").unwrap();
+        assert_eq!(
+            (value.as_str(), source),
+            ("127.0.0.1:1", &Source::File(path.clone()))
+        );
+        let (value, source) = config.raw("max_sessions_per_connection").unwrap();
+        assert_eq!(
+            (value.as_str(), source),
+            ("2", &Source::Env("MAX_SESSIONS_PER_CONNECTION"))
+        );
+        let (value, source) = config.raw("limits.max_stream_bytes").unwrap();
+        assert_eq!(
+            (value.as_str(), source),
+            ("3", &Source::Cli("--limits-max-stream-bytes".into()))
+        );
+        let (value, source) = config.raw("health_listen").unwrap();
+        assert_eq!(source, &Source::Default);
+        assert_eq!(Some(value.as_str()), find("health_listen").unwrap().default);
+    }
+
+    #[test]
+    fn config_flag_wins_over_config_file_env() {
+        let dir = TempDir::new().unwrap();
+        let from_env = config_file(&dir, "env.toml", "max_sessions_per_connection = 1\n");
+        let from_flag = config_file(&dir, "flag.toml", "max_sessions_per_connection = 2\n");
+        let env = |name: &str| (name == "CONFIG_FILE").then(|| from_env.display().to_string());
+        let config =
+            Config::resolve(args(&[&format!("--config={}", from_flag.display())]), env).unwrap();
+        assert_eq!(
+            config
+                .require::<u32>("max_sessions_per_connection")
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn unknown_file_keys_and_bad_values_name_their_source() {
+        let dir = TempDir::new().unwrap();
+        let path = config_file(&dir, "unknown.toml", "[limits]\nno_such_limit = 1\n");
+        let env = |name: &str| (name == "CONFIG_FILE").then(|| path.display().to_string());
+        let err = Config::resolve(Vec::new(), env).err().unwrap();
+        assert!(format!("{err:#}").contains("unknown option `limits.no_such_limit`"));
+
+        let env = |name: &str| (name == "MAX_SESSIONS_PER_CONNECTION").then(|| "many".to_string());
+        let config = Config::resolve(Vec::new(), env).unwrap();
+        let err = config
+            .require::<u32>("max_sessions_per_connection")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("environment variable MAX_SESSIONS_PER_CONNECTION"));
+    }
+}
@@ -0,0 +1,420 @@
+use super::session::{decode_varint, encode_varint};
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use sec_http3::error::Code;
+use sec_http3::quic::{self, RecvStream as _, SendStream as _, SendStreamUnframed as _};
+use sec_http3::quic::{StreamId, WriteBuf};
+use sec_http3::sec_http3_quinn as h3_quinn;
+use sec_http3::webtransport::SessionId;
+use std::future::poll_fn;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use tokio::sync::mpsc;
+use tracing::{warn, Instrument};
+
+// Stream type of WebTransport unidirectional streams, and signal value that starts WebTransport
+// bidirectional streams, from draft-ietf-webtrans-http3.
+const WEBTRANSPORT_UNI_STREAM: u64 = 0x54;
+const WEBTRANSPORT_BIDI_STREAM: u64 = 0x41;
+
+pub(crate) type QuicSendStream = h3_quinn::SendStream<Bytes>;
+
+/// A stream the peer opened for a WebTransport session, with the session named in its header.
+pub(crate) enum WebTransportStream {
+    Uni(SessionId, QuicRecvStream),
+    Bidi(SessionId, QuicSendStream, QuicRecvStream),
+}
+
+/// Receive half of a QUIC stream, which first returns what was read from the stream to tell
+/// what it carries.
+pub(crate) struct QuicRecvStream {
+    read: Option<Bytes>,
+    inner: h3_quinn::RecvStream,
+}
+
+impl QuicRecvStream {
+    fn new(inner: h3_quinn::RecvStream, read: Bytes) -> Self {
+        Self {
+            read: (!read.is_empty()).then_some(read),
+            inner,
+        }
+    }
+}
+
+impl quic::RecvStream for QuicRecvStream {
+    type Buf = Bytes;
+    type Error = h3_quinn::ReadError;
+
+    fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, Self::Error>> {
+        if let Some(read) = self.read.take() {
+            return Poll::Ready(Ok(Some(read)));
+        }
+        self.inner.poll_data(cx)
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.inner.stop_sending(error_code)
+    }
+
+    fn recv_id(&self) -> StreamId {
+        self.inner.recv_id()
+    }
+}
+
+/// Request stream, or any other bidirectional stream that is not WebTransport's, handed to the
+/// HTTP/3 server.
+pub(crate) struct RequestBidiStream {
+    send: QuicSendStream,
+    recv: QuicRecvStream,
+}
+
+impl From<h3_quinn::BidiStream<Bytes>> for RequestBidiStream {
+    fn from(stream: h3_quinn::BidiStream<Bytes>) -> Self {
+        let (send, recv) = quic::BidiStream::split(stream);
+        Self {
+            send,
+            recv: QuicRecvStream::new(recv, Bytes::new()),
+        }
+    }
+}
+
+impl quic::BidiStream<Bytes> for RequestBidiStream {
+    type SendStream = QuicSendStream;
+    type RecvStream = QuicRecvStream;
+
+    fn split(self) -> (QuicSendStream, QuicRecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl quic::RecvStream for RequestBidiStream {
+    type Buf = Bytes;
+    type Error = h3_quinn::ReadError;
+
+    fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, Self::Error>> {
+        self.recv.poll_data(cx)
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.recv.stop_sending(error_code)
+    }
+
+    fn recv_id(&self) -> StreamId {
+        self.recv.recv_id()
+    }
+}
+
+impl quic::SendStream<Bytes> for RequestBidiStream {
+    type Error = h3_quinn::SendStreamError;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send.poll_ready(cx)
+    }
+
+    fn send_data<T: Into<WriteBuf<Bytes>>>(&mut self, data: T) -> Result<(), Self::Error> {
+        self.send.send_data(data)
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send.poll_finish(cx)
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        self.send.reset(reset_code)
+    }
+
+    fn send_id(&self) -> StreamId {
+        self.send.send_id()
+    }
+}
+
+/// Opens the streams of the HTTP/3 server.
+pub(crate) struct RequestOpener(h3_quinn::OpenStreams);
+
+impl quic::OpenStreams<Bytes> for RequestOpener {
+    type BidiStream = RequestBidiStream;
+    type SendStream = QuicSendStream;
+    type RecvStream = QuicRecvStream;
+    type Error = h3_quinn::ConnectionError;
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<RequestBidiStream, Self::Error>> {
+        let stream = ready!(quic::OpenStreams::<Bytes>::poll_open_bidi(&mut self.0, cx))?;
+        Poll::Ready(Ok(stream.into()))
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<QuicSendStream, Self::Error>> {
+        quic::OpenStreams::<Bytes>::poll_open_send(&mut self.0, cx)
+    }
+
+    fn close(&mut self, code: Code, reason: &[u8]) {
+        quic::OpenStreams::<Bytes>::close(&mut self.0, code, reason)
+    }
+}
+
+type Accepted<T> = Result<T, h3_quinn::ConnectionError>;
+
+/// The QUIC connection as the HTTP/3 server sees it: it accepts every stream the peer opens
+/// except those of WebTransport sessions, which `split` hands out separately.
+pub(crate) struct Http3Connection {
+    quic: h3_quinn::Connection,
+    bidi: mpsc::UnboundedReceiver<Accepted<RequestBidiStream>>,
+    uni: mpsc::UnboundedReceiver<Accepted<QuicRecvStream>>,
+}
+
+impl quic::Connection<Bytes> for Http3Connection {
+    type BidiStream = RequestBidiStream;
+    type SendStream = QuicSendStream;
+    type RecvStream = QuicRecvStream;
+    type OpenStreams = RequestOpener;
+    type Error = h3_quinn::ConnectionError;
+
+    fn poll_accept_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<QuicRecvStream>, Self::Error>> {
+        Poll::Ready(ready!(self.uni.poll_recv(cx)).transpose())
+    }
+
+    fn poll_accept_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<RequestBidiStream>, Self::Error>> {
+        Poll::Ready(ready!(self.bidi.poll_recv(cx)).transpose())
+    }
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<RequestBidiStream, Self::Error>> {
+        let opened = quic::Connection::<Bytes>::poll_open_bidi(&mut self.quic, cx);
+        let stream = ready!(opened)?;
+        Poll::Ready(Ok(stream.into()))
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<QuicSendStream, Self::Error>> {
+        quic::Connection::<Bytes>::poll_open_send(&mut self.quic, cx)
+    }
+
+    fn opener(&self) -> RequestOpener {
+        RequestOpener(quic::Connection::<Bytes>::opener(&self.quic))
+    }
+
+    fn close(&mut self, code: Code, reason: &[u8]) {
+        quic::Connection::<Bytes>::close(&mut self.quic, code, reason)
+    }
+}
+
+/// The WebTransport side of a connection split by `split`.
+pub(crate) struct WebTransportStreams {
+    pub(crate) incoming: mpsc::UnboundedReceiver<WebTransportStream>,
+    pub(crate) opener: h3_quinn::OpenStreams,
+}
+
+/// Splits the streams the peer opens on `conn` by their header. Those of WebTransport sessions
+/// come out of `WebTransportStreams`, and the HTTP/3 server accepts every other one from the
+/// returned connection, CONNECT request streams included, so it answers every session.
+///
+/// Every stream waits for its header on its own, so a peer that is slow to send one holds up no
+/// other stream.
+pub(crate) fn split(conn: &quinn::Connection) -> (Http3Connection, WebTransportStreams) {
+    let (bidi_tx, bidi) = mpsc::unbounded_channel();
+    let (uni_tx, uni) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming) = mpsc::unbounded_channel();
+    tokio::spawn(accept_streams(conn.clone(), bidi_tx, uni_tx, incoming_tx).in_current_span());
+    let quic = h3_quinn::Connection::new(conn.clone());
+    let opener = quic::Connection::<Bytes>::opener(&quic);
+    (
+        Http3Connection { quic, bidi, uni },
+        WebTransportStreams { incoming, opener },
+    )
+}
+
+enum Classified {
+    Request(RequestBidiStream),
+    Control(QuicRecvStream),
+    WebTransport(WebTransportStream),
+}
+
+async fn accept_streams(
+    conn: quinn::Connection,
+    bidi: mpsc::UnboundedSender<Accepted<RequestBidiStream>>,
+    uni: mpsc::UnboundedSender<Accepted<QuicRecvStream>>,
+    webtransport: mpsc::UnboundedSender<WebTransportStream>,
+) {
+    // One connection per direction, as accepting either takes it mutably.
+    let mut accept_bidi = h3_quinn::Connection::new(conn.clone());
+    let mut accept_uni = h3_quinn::Connection::new(conn);
+    let mut classifying: FuturesUnordered<BoxFuture<'static, Option<Classified>>> =
+        FuturesUnordered::new();
+    let (mut accepting_bidi, mut accepting_uni) = (true, true);
+    while accepting_bidi || accepting_uni || !classifying.is_empty() {
+        tokio::select! {
+            accepted = poll_fn(|cx| {
+                quic::Connection::<Bytes>::poll_accept_bidi(&mut accept_bidi, cx)
+            }), if accepting_bidi => match accepted {
+                Ok(Some(stream)) => classifying.push(classify_bidi(stream).boxed()),
+                Ok(None) => accepting_bidi = false,
+                Err(err) => {
+                    let _ = bidi.send(Err(err));
+                    accepting_bidi = false;
+                }
+            },
+            accepted = poll_fn(|cx| {
+                quic::Connection::<Bytes>::poll_accept_recv(&mut accept_uni, cx)
+            }), if accepting_uni => match accepted {
+                Ok(Some(stream)) => classifying.push(classify_uni(stream).boxed()),
+                Ok(None) => accepting_uni = false,
+                Err(err) => {
+                    let _ = uni.send(Err(err));
+                    accepting_uni = false;
+                }
+            },
+            // Sends fail once the other side is gone, which drops the stream with it.
+            Some(classified) = classifying.next() => match classified {
+                Some(Classified::Request(stream)) => {
+                    let _ = bidi.send(Ok(stream));
+                }
+                Some(Classified::Control(stream)) => {
+                    let _ = uni.send(Ok(stream));
+                }
+                Some(Classified::WebTransport(stream)) => {
+                    let _ = webtransport.send(stream);
+                }
+                None => {}
+            },
+        }
+    }
+}
+
+async fn classify_bidi(stream: h3_quinn::BidiStream<Bytes>) -> Option<Classified> {
+    let (send, mut recv) = quic::BidiStream::split(stream);
+    let header = read_header(&mut recv, WEBTRANSPORT_BIDI_STREAM).await?;
+    Some(match header {
+        Header::WebTransport(session_id, read) => Classified::WebTransport(
+            WebTransportStream::Bidi(session_id, send, QuicRecvStream::new(recv, read)),
+        ),
+        Header::Other(read) => Classified::Request(RequestBidiStream {
+            send,
+            recv: QuicRecvStream::new(recv, read),
+        }),
+    })
+}
+
+async fn classify_uni(mut recv: h3_quinn::RecvStream) -> Option<Classified> {
+    let header = read_header(&mut recv, WEBTRANSPORT_UNI_STREAM).await?;
+    Some(match header {
+        Header::WebTransport(session_id, read) => Classified::WebTransport(
+            WebTransportStream::Uni(session_id, QuicRecvStream::new(recv, read)),
+        ),
+        Header::Other(read) => Classified::Control(QuicRecvStream::new(recv, read)),
+    })
+}
+
+enum Header {
+    /// A stream of a WebTransport session, with what was read past its header.
+    WebTransport(SessionId, Bytes),
+    /// Any other stream, with everything read from it.
+    Other(Bytes),
+}
+
+/// Reads the header of a stream, which starts with `signal` on WebTransport streams. Returns
+/// `None` for streams that end before their header, which are dropped.
+async fn read_header(recv: &mut h3_quinn::RecvStream, signal: u64) -> Option<Header> {
+    let mut read = BytesMut::new();
+    loop {
+        let mut header = &read[..];
+        match decode_varint(&mut header) {
+            Some(kind) if kind != signal => return Some(Header::Other(read.freeze())),
+            Some(_) => {
+                if let Some(connect_stream_id) = decode_varint(&mut header) {
+                    let header_len = read.len() - header.len();
+                    let Some(session_id) = session_id(connect_stream_id) else {
+                        warn!("Dropping stream of invalid session {connect_stream_id}");
+                        return None;
+                    };
+                    return Some(Header::WebTransport(
+                        session_id,
+                        read.split_off(header_len).freeze(),
+                    ));
+                }
+            }
+            None => {}
+        }
+        match poll_fn(|cx| recv.poll_data(cx)).await {
+            Ok(Some(chunk)) => read.extend_from_slice(&chunk),
+            Ok(None) | Err(_) => return None,
+        }
+    }
+}
+
+// Stream headers name a session by the id of its CONNECT stream, while sessions are registered,
+// and their datagrams framed, by its index.
+fn session_id(connect_stream_id: u64) -> Option<SessionId> {
+    // CONNECT requests only come on client-initiated bidirectional streams.
+    if connect_stream_id & 0b11 != 0 {
+        return None;
+    }
+    SessionId::try_from(connect_stream_id >> 2).ok()
+}
+
+/// Opens the streams of one session.
+pub(crate) struct StreamOpener {
+    // Only taken to clone a fresh opener, so concurrent opens do not wait for each other.
+    opener: Mutex<h3_quinn::OpenStreams>,
+    quarter_stream_id: u64,
+}
+
+impl StreamOpener {
+    pub(crate) fn new(opener: h3_quinn::OpenStreams, quarter_stream_id: u64) -> Self {
+        Self {
+            opener: Mutex::new(opener),
+            quarter_stream_id,
+        }
+    }
+
+    fn opener(&self) -> h3_quinn::OpenStreams {
+        self.opener.lock().unwrap().clone()
+    }
+
+    pub(crate) async fn open_uni(&self) -> Result<QuicSendStream> {
+        let mut opener = self.opener();
+        let mut send =
+            poll_fn(|cx| quic::OpenStreams::<Bytes>::poll_open_send(&mut opener, cx)).await?;
+        self.write_header(&mut send, WEBTRANSPORT_UNI_STREAM)
+            .await?;
+        Ok(send)
+    }
+
+    pub(crate) async fn open_bi(&self) -> Result<(QuicSendStream, QuicRecvStream)> {
+        let mut opener = self.opener();
+        let stream =
+            poll_fn(|cx| quic::OpenStreams::<Bytes>::poll_open_bidi(&mut opener, cx)).await?;
+        let (mut send, recv) = quic::BidiStream::split(stream);
+        self.write_header(&mut send, WEBTRANSPORT_BIDI_STREAM)
+            .await?;
+        Ok((send, QuicRecvStream::new(recv, Bytes::new())))
+    }
+
+    async fn write_header(&self, send: &mut QuicSendStream, signal: u64) -> Result<()> {
+        let mut header = BytesMut::new();
+        encode_varint(&mut header, signal);
+        encode_varint(&mut header, self.quarter_stream_id << 2);
+        let mut header = header.freeze();
+        while header.has_remaining() {
+            poll_fn(|cx| send.poll_send(cx, &mut header)).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+use super::close::{SessionClose, SessionEnd};
+use super::demux::StreamOpener;
+use super::handshake::{Handshake, HandshakeState};
+use super::metrics::Metrics;
+use super::rate_limit::SessionLimits;
+use super::router::RequestParams;
+use super::session::{DatagramSender, EndSender};
+use super::stream::{RawRecvStream, RawSendStream, RecvStream, SendStream, StreamError};
+use super::tls;
+use super::transport::{self, CongestionController};
+use super::ConnectionConfig;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::Extensions;
+use sec_http3::webtransport::SessionId;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tracing::info;
+
+/// Shared application state handed to every session, keyed by type.
+#[derive(Clone, Default)]
+pub struct AppState(Arc<Extensions>);
+
+impl AppState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value to the state. Must be called before the state is shared with the server.
+    pub fn insert<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        Arc::get_mut(&mut self.0)
+            .expect("AppState cannot be modified once it is shared")
+            .insert(value);
+        self
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.0.get::<T>()
+    }
+}
+
+impl std::fmt::Debug for AppState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppState").finish_non_exhaustive()
+    }
+}
+
+/// What the QUIC handshake established about the peer, shared by every session on a
+/// connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    conn: quinn::Connection,
+    server_name: OnceLock<Option<String>>,
+    peer_subject: OnceLock<Option<String>>,
+    handshake: Handshake,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(conn: &quinn::Connection, handshake: Handshake) -> Self {
+        Self {
+            conn: conn.clone(),
+            server_name: OnceLock::new(),
+            peer_subject: OnceLock::new(),
+            handshake,
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    /// Server name the client asked for with SNI, which selected the certificate served.
+    pub fn server_name(&self) -> Option<&str> {
+        // Connections are accepted once the ClientHello that carries it was processed.
+        self.server_name
+            .get_or_init(|| {
+                self.conn
+                    .handshake_data()?
+                    .downcast::<quinn::crypto::rustls::HandshakeData>()
+                    .ok()?
+                    .server_name
+            })
+            .as_deref()
+    }
+
+    /// Subject of the client certificate, e.g. `CN=billing, O=Example`. Only set when client
+    /// authentication is enabled and the peer presented a certificate, which rustls has
+    /// verified against the client CAs by the time the handshake completes. With 0-RTT the
+    /// certificate arrives after the early data, so this is `None` until
+    /// `handshake_complete` returns.
+    pub fn peer_subject(&self) -> Option<&str> {
+        if self.handshake.state() != HandshakeState::Complete {
+            return None;
+        }
+        self.peer_subject
+            .get_or_init(|| {
+                let chain = self
+                    .conn
+                    .peer_identity()?
+                    .downcast::<Vec<rustls::Certificate>>()
+                    .ok()?;
+                tls::subject(&chain.first()?.0)
+            })
+            .as_deref()
+    }
+
+    pub fn handshake_state(&self) -> HandshakeState {
+        self.handshake.state()
+    }
+
+    /// Waits until the client finished the handshake, after which nothing it sent can be a
+    /// replay. Fails when the connection closes first. Returns at once without 0-RTT.
+    pub async fn handshake_complete(&self) -> Result<()> {
+        self.handshake.wait().await
+    }
+}
+
+/// Per-session handle passed to every `SessionHandler` callback.
+#[derive(Clone)]
+pub struct SessionContext {
+    streams: Arc<StreamOpener>,
+    session_id: SessionId,
+    datagrams: DatagramSender,
+    connection: Arc<ConnectionInfo>,
+    params: Arc<RequestParams>,
+    state: AppState,
+    metrics: Arc<Metrics>,
+    limits: Arc<SessionLimits>,
+    ends: EndSender,
+    early_data: bool,
+}
+
+impl SessionContext {
+    pub(crate) fn new(
+        streams: StreamOpener,
+        session_id: SessionId,
+        datagrams: DatagramSender,
+        connection: Arc<ConnectionInfo>,
+        params: RequestParams,
+        ends: EndSender,
+        config: &ConnectionConfig,
+    ) -> Self {
+        Self {
+            streams: Arc::new(streams),
+            session_id,
+            datagrams,
+            connection,
+            params: Arc::new(params),
+            state: config.router.state().clone(),
+            metrics: config.metrics.clone(),
+            limits: Arc::new(SessionLimits::new(
+                &config.rate_limits,
+                config.metrics.clone(),
+            )),
+            ends,
+            early_data: connection.handshake_state() == HandshakeState::EarlyData,
+        }
+    }
+
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    pub fn connection(&self) -> &ConnectionInfo {
+        &self.connection
+    }
+
+    /// Whether the CONNECT request of this session was handled before the client finished the
+    /// handshake, which only happens when it arrived as 0-RTT data. Such a request may be a
+    /// replay, and so may the session's own early data.
+    pub fn early_data(&self) -> bool {
+        self.early_data
+    }
+
+    /// Guards a single operation that must not run on replayed data, such as a write, in a
+    /// handler that is otherwise idempotent. Waits until the client finished the handshake, and
+    /// fails if it never does. Returns at once unless the session carries early data.
+    pub async fn replay_safe(&self) -> Result<()> {
+        self.connection.handshake_complete().await
+    }
+
+    /// Path parameters and query string of the CONNECT request.
+    pub fn request(&self) -> &RequestParams {
+        &self.params
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Rate limits and byte caps of this session. Incoming streams are charged as they are read.
+    pub fn limits(&self) -> &SessionLimits {
+        &self.limits
+    }
+
+    pub fn send_datagram(&self, buf: Bytes) -> Result<()> {
+        self.datagrams.send(buf)
+    }
+
+    /// Replaces the congestion controller of the QUIC connection carrying this session, e.g.
+    /// for A/B experiments. This affects every session on the connection.
+    pub fn set_congestion_controller(&self, controller: CongestionController) -> Result<()> {
+        transport::set_congestion_controller(self.datagrams.connection(), controller)
+    }
+
+    /// Closes the session with an application error code and reason, which the peer receives
+    /// in a CLOSE_WEBTRANSPORT_SESSION capsule. `on_close` follows once the session is gone.
+    pub fn close(&self, code: u32, reason: impl Into<String>) {
+        // Fails only once the connection is gone, which closes the session anyway.
+        let close = SessionClose::new(code, reason);
+        let _ = self
+            .ends
+            .send((self.session_id, SessionEnd::ClosedLocally(close)));
+    }
+
+    pub async fn open_uni(&self) -> Result<SendStream> {
+        let stream = self.streams.open_uni().await?;
+        Ok(SendStream::new(
+            stream,
+            Arc::default(),
+            self.metrics.clone(),
+        ))
+    }
+
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream)> {
+        let (send, recv) = self.streams.open_bi().await?;
+        Ok(self.wrap_bidi(send, recv))
+    }
+
+    /// Hands both halves of a bidirectional stream over to a handler, charging what it reads to
+    /// this session's limits.
+    pub(crate) fn wrap_bidi(
+        &self,
+        send: RawSendStream,
+        recv: RawRecvStream,
+    ) -> (SendStream, RecvStream) {
+        let cutoff = Arc::default();
+        (
+            SendStream::new(send, Arc::clone(&cutoff), self.metrics.clone()),
+            RecvStream::new(recv, &self.limits, cutoff, self.metrics.clone()),
+        )
+    }
+
+    pub(crate) fn wrap_uni(&self, recv: RawRecvStream) -> RecvStream {
+        RecvStream::new(recv, &self.limits, Arc::default(), self.metrics.clone())
+    }
+}
+
+/// Application logic for a WebTransport session.
+///
+/// Datagrams are delivered in order on the session task, while every incoming stream is
+/// handled on its own task so a slow stream does not block the rest of the session. A single
+/// QUIC connection may carry several sessions, each with its own `SessionContext`.
+#[async_trait]
+pub trait SessionHandler: Send + Sync + 'static {
+    /// Whether every callback of this handler is idempotent, so it is safe to run on 0-RTT
+    /// data that an attacker may have replayed. Otherwise the callbacks of a connection still
+    /// in its handshake wait until it completes, and never run if it fails.
+    ///
+    /// This is decided per handler because a callback is handed a stream or datagram before
+    /// anything in it tells what operation it asks for. Idempotent handlers guard the single
+    /// operations that are not, such as writes, with `SessionContext::replay_safe` once they
+    /// know.
+    fn idempotent(&self) -> bool {
+        false
+    }
+
+    async fn on_open(&self, _ctx: &SessionContext) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_datagram(&self, ctx: &SessionContext, datagram: Bytes) -> Result<()>;
+
+    async fn on_uni_stream(&self, ctx: &SessionContext, stream: RecvStream) -> Result<()>;
+
+    async fn on_bidi_stream(
+        &self,
+        ctx: &SessionContext,
+        send: SendStream,
+        recv: RecvStream,
+    ) -> Result<()>;
+
+    /// Called once when the server starts shutting down. The session stays open until the peer
+    /// closes it or the shutdown grace period runs out.
+    async fn on_drain(&self, _ctx: &SessionContext) {}
+
+    /// Called exactly once per session, after every other callback of the session task
+    /// returned, with how the session ended.
+    async fn on_close(&self, _ctx: &SessionContext, _end: &SessionEnd) {}
+}
+
+/// Echoes every datagram and stream back to the peer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EchoHandler;
+
+#[async_trait]
+impl SessionHandler for EchoHandler {
+    // A replayed echo only goes back to the client that sent the data in the first place.
+    fn idempotent(&self) -> bool {
+        true
+    }
+
+    async fn on_datagram(&self, ctx: &SessionContext, datagram: Bytes) -> Result<()> {
+        info!("Echoing datagram: {:?}", datagram);
+        ctx.send_datagram(datagram)
+    }
+
+    async fn on_uni_stream(&self, ctx: &SessionContext, mut stream: RecvStream) -> Result<()> {
+        info!("Echoing unidirectional stream");
+        let mut send = ctx.open_uni().await?;
+        echo_stream(&mut send, &mut stream).await
+    }
+
+    async fn on_bidi_stream(
+        &self,
+        ctx: &SessionContext,
+        mut send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<()> {
+        info!("Echoing bidirectional stream");
+        echo_stream(&mut send, &mut recv).await
+    }
+}
+
+/// Writes every chunk read from `recv` to `send` as it arrives, so memory use is bounded by
+/// the QUIC flow control windows rather than the length of the stream. The next chunk is only
+/// read once the previous one was accepted by `send`.
+///
+/// A FIN from the peer finishes `send`, and a reset is forwarded with the same error code.
+/// When the peer stops reading the echo, `recv` is stopped in turn. A stream over the
+/// session's limits has already been stopped and reset by the time its read fails.
+async fn echo_stream(send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
+    loop {
+        let chunk = match recv.read_chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                if let Some(code) = err.err_code() {
+                    send.reset(code);
+                }
+                if let StreamError::Limit(_) = err {
+                    return Ok(());
+                }
+                return Err(anyhow!(err).context("failed to read stream"));
+            }
+        };
+        if let Err(err) = send.write_all(chunk).await {
+            if let Some(code) = err.err_code() {
+                recv.stop_sending(code);
+            }
+            return Err(anyhow!(err).context("failed to write stream"));
+        }
+    }
+    send.finish()
+        .await
+        .map_err(|err| anyhow!(err).context("failed to finish stream"))
+}
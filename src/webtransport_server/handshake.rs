@@ -0,0 +1,107 @@
+use anyhow::{bail, Result};
+use std::future::Future;
+use tokio::sync::watch;
+
+/// How far the QUIC handshake of a connection got, as seen by the sessions it carries.
+///
+/// With 0-RTT enabled, connections are accepted as soon as the ClientHello is processed, so
+/// sessions may be opened and fed data before the client proved it is live by finishing the
+/// handshake. Everything received in that window was sent as 0-RTT data, which an attacker
+/// who captured it can replay to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    /// The handshake is still in progress, so data received so far may be a replay.
+    EarlyData,
+    /// The client finished the handshake. Data it sends from now on cannot be replayed.
+    Complete,
+    /// The connection closed before the handshake completed, which is what a replayed
+    /// handshake looks like.
+    Failed,
+}
+
+/// Follows the handshake state of one connection.
+#[derive(Debug, Clone)]
+pub(crate) struct Handshake {
+    state: watch::Receiver<HandshakeState>,
+    // Set for connections accepted before their handshake completed.
+    conn: Option<quinn::Connection>,
+}
+
+impl Handshake {
+    /// For connections accepted once their handshake completed.
+    pub(crate) fn complete() -> Self {
+        Self {
+            state: watch::channel(HandshakeState::Complete).1,
+            conn: None,
+        }
+    }
+
+    /// For connections accepted with `quinn::Connecting::into_0rtt`. The returned future drives
+    /// the state and resolves once the handshake is over, with whether it completed.
+    pub(crate) fn early(
+        conn: quinn::Connection,
+        accepted: quinn::ZeroRttAccepted,
+    ) -> (Self, impl Future<Output = bool>) {
+        let (tx, state) = watch::channel(HandshakeState::EarlyData);
+        let handshake = Self {
+            state,
+            conn: Some(conn.clone()),
+        };
+        let done = async move {
+            // On servers this resolves when the handshake completes or the connection closes
+            // first, and its value is always false.
+            accepted.await;
+            let completed = completed(&conn);
+            tx.send_replace(if completed {
+                HandshakeState::Complete
+            } else {
+                HandshakeState::Failed
+            });
+            completed
+        };
+        (handshake, done)
+    }
+
+    /// The state right now. The task driving the state only runs some time after the
+    /// handshake completed, so until it did, rustls is asked directly.
+    pub(crate) fn state(&self) -> HandshakeState {
+        let state = *self.state.borrow();
+        match &self.conn {
+            Some(conn) if state == HandshakeState::EarlyData && tls_complete(conn) => {
+                HandshakeState::Complete
+            }
+            _ => state,
+        }
+    }
+
+    /// Waits until the handshake is over, and fails if it did not complete.
+    pub(crate) async fn wait(&self) -> Result<()> {
+        let mut rx = self.state.clone();
+        let state = match rx
+            .wait_for(|state| *state != HandshakeState::EarlyData)
+            .await
+        {
+            Ok(state) => *state,
+            Err(_) => HandshakeState::Failed,
+        };
+        if state == HandshakeState::Failed {
+            bail!("the QUIC handshake did not complete");
+        }
+        Ok(())
+    }
+}
+
+// Whether rustls processed the Finished message of the client. Keying material can only be
+// exported from then on, which makes this exact, unlike anything quinn reports.
+fn tls_complete(conn: &quinn::Connection) -> bool {
+    conn.export_keying_material(&mut [0; 1], b"EXPORTER-handshake-complete", b"")
+        .is_ok()
+}
+
+// Whether the handshake of a connection whose `ZeroRttAccepted` resolved had completed. quinn
+// sends HANDSHAKE_DONE as soon as it completes, before waking the future, unless congestion
+// holds the frame back. A connection that is still open has completed either way, as the
+// future only resolves early when the connection closes.
+fn completed(conn: &quinn::Connection) -> bool {
+    conn.stats().frame_tx.handshake_done > 0 || conn.close_reason().is_none()
+}
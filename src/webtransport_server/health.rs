@@ -0,0 +1,205 @@
+use super::session::{decode_varint, encode_varint};
+use super::{CertResolver, Shutdown, TokenAuth};
+use crate::api::ACCESS_TOKEN_PARAM;
+use anyhow::{bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use http::{Method, Request, StatusCode};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ServerName};
+use sec_http3::ext::Protocol;
+use sec_http3::sec_http3_quinn as h3_quinn;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+// How long the loopback round trip may take before the endpoint is reported as not ready.
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(2);
+const LOOPBACK_PAYLOAD: &[u8] = b"readyz";
+
+/// State behind `/livez` and `/readyz`.
+///
+/// The endpoint is live until `start` fails, and ready once the TLS config is loaded and the
+/// UDP socket is bound, until a shutdown starts draining it.
+pub(crate) struct Health {
+    shutdown: Shutdown,
+    cert_resolver: OnceLock<Arc<CertResolver>>,
+    endpoint: OnceLock<SocketAddr>,
+    failure: OnceLock<String>,
+    loopback_path: Option<String>,
+    loopback_origin: Option<String>,
+    token_auth: Option<Arc<TokenAuth>>,
+}
+
+impl Health {
+    pub(crate) fn new(
+        shutdown: Shutdown,
+        loopback_path: Option<String>,
+        loopback_origin: Option<String>,
+        token_auth: Option<Arc<TokenAuth>>,
+    ) -> Self {
+        Self {
+            shutdown,
+            cert_resolver: OnceLock::new(),
+            endpoint: OnceLock::new(),
+            failure: OnceLock::new(),
+            loopback_path,
+            loopback_origin,
+            token_auth,
+        }
+    }
+
+    pub(crate) fn tls_loaded(&self, cert_resolver: Arc<CertResolver>) {
+        let _ = self.cert_resolver.set(cert_resolver);
+    }
+
+    pub(crate) fn cert_resolver(&self) -> Option<&CertResolver> {
+        self.cert_resolver.get().map(|resolver| resolver.as_ref())
+    }
+
+    pub(crate) fn endpoint_bound(&self, local_addr: SocketAddr) {
+        let _ = self.endpoint.set(local_addr);
+    }
+
+    pub(crate) fn failed(&self, err: &anyhow::Error) {
+        let _ = self.failure.set(format!("{err:#}"));
+    }
+
+    pub(crate) fn live(&self) -> Result<()> {
+        match self.failure.get() {
+            Some(failure) => bail!("QUIC endpoint failed: {failure}"),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) async fn ready(&self) -> Result<()> {
+        self.live()?;
+        if self.shutdown.is_triggered() {
+            bail!("draining for shutdown");
+        }
+        let cert_resolver = self.cert_resolver().context("TLS config is not loaded")?;
+        let local_addr = *self.endpoint.get().context("QUIC endpoint is not bound")?;
+        if let Some(path) = &self.loopback_path {
+            tokio::time::timeout(
+                LOOPBACK_TIMEOUT,
+                loopback_echo(
+                    local_addr,
+                    cert_resolver.certificate_hash(),
+                    path,
+                    self.loopback_origin.as_deref(),
+                    self.token_auth.as_ref().map(|auth| auth.issue(None, path)),
+                ),
+            )
+            .await
+            .context("loopback WebTransport echo timed out")?
+            .context("loopback WebTransport echo failed")?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens a WebTransport session to `path` on our own endpoint and waits for a datagram to be
+/// echoed back, which proves that the UDP path and the handshake work end to end. `origin` is
+/// sent so the session passes `allowed_origins`.
+async fn loopback_echo(
+    local_addr: SocketAddr,
+    certificate_hash: Vec<u8>,
+    path: &str,
+    origin: Option<&str>,
+    token: Option<String>,
+) -> Result<()> {
+    let target = match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, local_addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, local_addr.port()).into(),
+        _ => local_addr,
+    };
+    let bind_addr: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate(certificate_hash)))
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+
+    let mut endpoint = quinn::Endpoint::client(bind_addr)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls_config)));
+    // An IP address as server name keeps SNI out of the handshake, so the default
+    // certificate is served.
+    let conn = endpoint
+        .connect(target, &target.ip().to_string())?
+        .await
+        .context("QUIC handshake failed")?;
+
+    let result = async {
+        let (mut driver, mut send_request) = sec_http3::client::builder()
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .build::<_, _, Bytes>(h3_quinn::Connection::new(conn.clone()))
+            .await?;
+        let driver = tokio::spawn(async move { driver.wait_idle().await });
+
+        let mut request = Request::builder().method(Method::CONNECT);
+        if let Some(origin) = origin {
+            request = request.header(http::header::ORIGIN, origin);
+        }
+        let request = request
+            .uri(match token {
+                Some(token) => format!("https://{target}{path}?{ACCESS_TOKEN_PARAM}={token}"),
+                None => format!("https://{target}{path}"),
+            })
+            .extension(Protocol::WEB_TRANSPORT)
+            .body(())?;
+        let mut stream = send_request.send_request(request).await?;
+        let response = stream.recv_response().await?;
+        if response.status() != StatusCode::OK {
+            bail!("CONNECT {path} answered with {}", response.status());
+        }
+
+        let quarter_stream_id = stream.id().index();
+        let mut datagram = BytesMut::new();
+        encode_varint(&mut datagram, quarter_stream_id);
+        datagram.extend_from_slice(LOOPBACK_PAYLOAD);
+        conn.send_datagram(datagram.freeze())?;
+        loop {
+            let mut echoed = conn.read_datagram().await?;
+            if decode_varint(&mut echoed) == Some(quarter_stream_id) && echoed == LOOPBACK_PAYLOAD {
+                break;
+            }
+        }
+        driver.abort();
+        Ok(())
+    }
+    .await;
+
+    conn.close(0u32.into(), b"readyz done");
+    endpoint.wait_idle().await;
+    result
+}
+
+// The loopback client only trusts the certificate our own resolver serves.
+struct PinnedCertificate(Vec<u8>);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let hash = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        if hash.as_ref() == self.0.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "loopback peer is not serving our certificate".into(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use prometheus::{
+    Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub(crate) const IN: &str = "in";
+pub(crate) const OUT: &str = "out";
+
+/// Prometheus metrics for the QUIC endpoint, served by the health server on `/metrics`.
+///
+/// Per-connection RTT, congestion window and lost packets are sampled from quinn when the
+/// metrics are scraped, and their series are removed once the connection closes.
+pub struct Metrics {
+    registry: Registry,
+    handshakes_accepted: IntCounter,
+    handshakes_failed: IntCounter,
+    zero_rtt_connections: IntCounter,
+    early_data_sessions: IntCounter,
+    active_connections: IntGauge,
+    active_sessions: IntGauge,
+    datagrams: IntCounterVec,
+    stream_bytes: IntCounterVec,
+    handler_errors: IntCounterVec,
+    origin_rejections: IntCounterVec,
+    rate_limited: IntCounterVec,
+    size_limit_exceeded: IntCounterVec,
+    connection_rtt: GaugeVec,
+    connection_cwnd: IntGaugeVec,
+    connection_lost_packets: IntGaugeVec,
+    connections: Mutex<HashMap<usize, quinn::Connection>>,
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self {
+            handshakes_accepted: IntCounter::new(
+                "webtransport_handshakes_accepted_total",
+                "QUIC handshakes that completed",
+            )
+            .unwrap(),
+            handshakes_failed: IntCounter::new(
+                "webtransport_handshakes_failed_total",
+                "QUIC handshakes that failed",
+            )
+            .unwrap(),
+            zero_rtt_connections: IntCounter::new(
+                "webtransport_zero_rtt_connections_total",
+                "Connections that opened a session in 0-RTT data",
+            )
+            .unwrap(),
+            early_data_sessions: IntCounter::new(
+                "webtransport_early_data_sessions_total",
+                "Sessions whose CONNECT request arrived as 0-RTT data",
+            )
+            .unwrap(),
+            active_connections: IntGauge::new(
+                "webtransport_active_connections",
+                "Open QUIC connections",
+            )
+            .unwrap(),
+            active_sessions: IntGauge::new(
+                "webtransport_active_sessions",
+                "Open WebTransport sessions",
+            )
+            .unwrap(),
+            datagrams: IntCounterVec::new(
+                Opts::new("webtransport_datagrams_total", "Datagrams by direction"),
+                &["direction"],
+            )
+            .unwrap(),
+            stream_bytes: IntCounterVec::new(
+                Opts::new(
+                    "webtransport_stream_bytes_total",
+                    "Stream payload bytes by direction",
+                ),
+                &["direction"],
+            )
+            .unwrap(),
+            handler_errors: IntCounterVec::new(
+                Opts::new(
+                    "webtransport_handler_errors_total",
+                    "Errors returned by session handlers, such as the echo handler, by kind",
+                ),
+                &["kind"],
+            )
+            .unwrap(),
+            origin_rejections: IntCounterVec::new(
+                Opts::new(
+                    "webtransport_origin_rejections_total",
+                    "CONNECT requests rejected because their Origin is missing or not allowed",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            rate_limited: IntCounterVec::new(
+                Opts::new(
+                    "webtransport_rate_limited_total",
+                    "Datagrams, streams, stream bytes, handshakes and connections over a limit",
+                ),
+                &["kind"],
+            )
+            .unwrap(),
+            size_limit_exceeded: IntCounterVec::new(
+                Opts::new(
+                    "webtransport_size_limit_exceeded_total",
+                    "Streams, sessions and datagrams cut off for exceeding a byte cap",
+                ),
+                &["kind"],
+            )
+            .unwrap(),
+            connection_rtt: GaugeVec::new(
+                Opts::new(
+                    "webtransport_connection_rtt_seconds",
+                    "Smoothed RTT of each connection",
+                ),
+                &["connection"],
+            )
+            .unwrap(),
+            connection_cwnd: IntGaugeVec::new(
+                Opts::new(
+                    "webtransport_connection_cwnd_bytes",
+                    "Congestion window of each connection",
+                ),
+                &["connection"],
+            )
+            .unwrap(),
+            connection_lost_packets: IntGaugeVec::new(
+                Opts::new(
+                    "webtransport_connection_lost_packets",
+                    "Packets lost so far on each connection",
+                ),
+                &["connection"],
+            )
+            .unwrap(),
+            connections: Mutex::new(HashMap::new()),
+            registry,
+        };
+        metrics.register_all();
+        metrics
+    }
+
+    fn register_all(&self) {
+        let collectors: [Box<dyn prometheus::core::Collector>; 15] = [
+            Box::new(self.handshakes_accepted.clone()),
+            Box::new(self.handshakes_failed.clone()),
+            Box::new(self.zero_rtt_connections.clone()),
+            Box::new(self.early_data_sessions.clone()),
+            Box::new(self.active_connections.clone()),
+            Box::new(self.active_sessions.clone()),
+            Box::new(self.datagrams.clone()),
+            Box::new(self.stream_bytes.clone()),
+            Box::new(self.handler_errors.clone()),
+            Box::new(self.origin_rejections.clone()),
+            Box::new(self.rate_limited.clone()),
+            Box::new(self.size_limit_exceeded.clone()),
+            Box::new(self.connection_rtt.clone()),
+            Box::new(self.connection_cwnd.clone()),
+            Box::new(self.connection_lost_packets.clone()),
+        ];
+        for collector in collectors {
+            self.registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.handshakes_failed.inc();
+    }
+
+    pub(crate) fn handshake_completed(&self) {
+        self.handshakes_accepted.inc();
+    }
+
+    /// Counts a connection that opened its first session in 0-RTT data. quinn cannot tell a
+    /// server whether it accepted 0-RTT, so a CONNECT request handled before the handshake
+    /// completed is what shows it was used.
+    pub(crate) fn zero_rtt_connection(&self) {
+        self.zero_rtt_connections.inc();
+    }
+
+    /// Tracks an accepted connection until it closes. With 0-RTT enabled this may happen
+    /// before its handshake completes.
+    pub(crate) fn connection_opened(self: &Arc<Self>, conn: &quinn::Connection) {
+        self.active_connections.inc();
+        let id = conn.stable_id();
+        self.connections.lock().unwrap().insert(id, conn.clone());
+        let metrics = self.clone();
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            conn.closed().await;
+            metrics.connection_closed(id);
+        });
+    }
+
+    fn connection_closed(&self, id: usize) {
+        self.active_connections.dec();
+        self.connections.lock().unwrap().remove(&id);
+        let label = id.to_string();
+        let _ = self.connection_rtt.remove_label_values(&[&label]);
+        let _ = self.connection_cwnd.remove_label_values(&[&label]);
+        let _ = self.connection_lost_packets.remove_label_values(&[&label]);
+    }
+
+    pub(crate) fn session_opened(&self, early_data: bool) {
+        self.active_sessions.inc();
+        if early_data {
+            self.early_data_sessions.inc();
+        }
+    }
+
+    pub(crate) fn session_closed(&self) {
+        self.active_sessions.dec();
+    }
+
+    pub(crate) fn datagram(&self, direction: &str) {
+        self.datagrams.with_label_values(&[direction]).inc();
+    }
+
+    pub(crate) fn handler_error(&self, kind: &str) {
+        self.handler_errors.with_label_values(&[kind]).inc();
+    }
+
+    pub(crate) fn origin_rejected(&self, reason: &str) {
+        self.origin_rejections.with_label_values(&[reason]).inc();
+    }
+
+    pub(crate) fn rate_limited(&self, kind: &str) {
+        self.rate_limited.with_label_values(&[kind]).inc();
+    }
+
+    pub(crate) fn size_limit_exceeded(&self, kind: &str) {
+        self.size_limit_exceeded.with_label_values(&[kind]).inc();
+    }
+
+    /// Counts stream payload handed to a handler.
+    pub fn stream_bytes_received(&self, bytes: usize) {
+        self.stream_bytes
+            .with_label_values(&[IN])
+            .inc_by(bytes as u64);
+    }
+
+    /// Counts stream payload a handler wrote.
+    pub fn stream_bytes_sent(&self, bytes: usize) {
+        self.stream_bytes
+            .with_label_values(&[OUT])
+            .inc_by(bytes as u64);
+    }
+
+    /// Samples the live connections and renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        for (id, conn) in self.connections.lock().unwrap().iter() {
+            let stats = conn.stats();
+            let label = id.to_string();
+            self.connection_rtt
+                .with_label_values(&[&label])
+                .set(stats.path.rtt.as_secs_f64());
+            self.connection_cwnd
+                .with_label_values(&[&label])
+                .set(stats.path.cwnd as i64);
+            self.connection_lost_packets
+                .with_label_values(&[&label])
+                .set(stats.path.lost_packets as i64);
+        }
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("metrics are valid");
+        String::from_utf8(buf).expect("metrics are UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
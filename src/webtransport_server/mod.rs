@@ -0,0 +1,526 @@
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use demux::{Http3Connection, WebTransportStreams};
+use handshake::Handshake;
+use health::Health;
+use http::Method;
+use leptos::LeptosOptions;
+use qlog::ConnectionLog;
+use rate_limit::IpLimits;
+use sec_http3::{error::ErrorLevel, ext::Protocol, server::Connection};
+use session::SessionDispatcher;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tls::KeyLogFile;
+use tokio::sync::mpsc;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+mod alt_svc;
+mod auth;
+mod certs;
+mod close;
+mod config;
+mod demux;
+mod handler;
+mod handshake;
+mod health;
+mod metrics;
+mod origin;
+mod qlog;
+mod rate_limit;
+mod router;
+mod session;
+mod shutdown;
+mod site;
+mod stream;
+mod telemetry;
+mod tls;
+mod transport;
+
+pub use alt_svc::{AltSvc, AltSvcOpt};
+pub use auth::{Claims, TokenAuth};
+pub use certs::{Certs, Material};
+pub use close::{SessionClose, SessionEnd, NO_ERROR_CLOSE_CODE, OPEN_FAILED_CLOSE_CODE};
+pub use config::{Config, Source};
+pub use handler::{AppState, ConnectionInfo, EchoHandler, SessionContext, SessionHandler};
+pub use handshake::HandshakeState;
+pub use metrics::Metrics;
+pub use origin::AllowedOrigin;
+pub use rate_limit::{
+    LimitAction, LimitExceeded, RateLimitOpt, SessionLimits, EXCESSIVE_LOAD_CLOSE_CODE,
+    MESSAGE_TOO_LARGE_ERROR_CODE, RATE_LIMITED_ERROR_CODE,
+};
+pub use router::{RequestParams, Router};
+pub use shutdown::{wait_for_signal, Shutdown, SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON};
+pub use stream::{RecvStream, SendStream, StreamError};
+pub use telemetry::{init_telemetry, LogFormat, Telemetry, TelemetryOpt};
+pub use tls::{CertResolver, ClientAuthMode, ClientAuthOpt, SelfSignedOpt, SniCert};
+pub use transport::{CongestionController, TransportOpt};
+
+#[derive(Debug)]
+pub struct WebTransportOpt {
+    pub listen: SocketAddr,
+    pub health_listen: SocketAddr,
+    pub certs: Certs,
+    /// When set, a generated certificate is served instead of `certs`.
+    pub self_signed: Option<SelfSignedOpt>,
+    /// Served instead of the default certificate to clients asking for a matching server name.
+    pub sni_certs: Vec<SniCert>,
+    pub client_auth: ClientAuthOpt,
+    /// Accept 0-RTT data from clients resuming a TLS session. Such data may be replayed by an
+    /// attacker, so it only reaches handlers marked `SessionHandler::idempotent` before the
+    /// handshake completes.
+    pub zero_rtt: bool,
+    /// Origins allowed to open WebTransport sessions. Every origin is allowed when empty.
+    pub allowed_origins: Vec<AllowedOrigin>,
+    /// When set, sessions are only accepted with a valid token.
+    pub token_auth: Option<Arc<TokenAuth>>,
+    /// How often to check the certificate files for changes. SIGHUP always triggers a reload.
+    pub cert_reload_interval: Option<Duration>,
+    pub max_sessions_per_connection: u64,
+    pub transport: TransportOpt,
+    pub rate_limits: RateLimitOpt,
+    /// When set, plain HTTP/3 requests are answered with the Leptos site.
+    pub site: Option<LeptosOptions>,
+    pub alt_svc: AltSvcOpt,
+    /// How long live sessions get to finish after a shutdown before their connections are closed.
+    pub shutdown_grace_period: Duration,
+    /// When set, `/readyz` also opens a loopback WebTransport session to this path, which must
+    /// echo datagrams, and sends one through it.
+    pub readiness_check_path: Option<String>,
+    /// Debugging only: TLS secrets are appended to this file so packet captures can be
+    /// decrypted, which means anyone who can read it can decrypt the traffic.
+    pub key_log_file: Option<PathBuf>,
+    /// Debugging only: every connection writes a qlog-style event log into this directory.
+    pub qlog_dir: Option<PathBuf>,
+}
+
+impl WebTransportOpt {
+    /// The `Alt-Svc` advertisement for this endpoint, to be installed on the actix server.
+    pub fn alt_svc(&self) -> AltSvc {
+        AltSvc::new(self.listen.port(), &self.alt_svc)
+    }
+
+    pub fn cert_resolver(&self) -> Result<CertResolver> {
+        let mut cert_resolver = match &self.self_signed {
+            Some(self_signed) => CertResolver::self_signed(self_signed.clone())?,
+            None => CertResolver::new(self.certs.clone())?,
+        };
+        for sni_cert in &self.sni_certs {
+            cert_resolver = cert_resolver.with_sni_cert(sni_cert.clone())?;
+        }
+        Ok(cert_resolver)
+    }
+
+    /// Loads the certificates and checks every setting `start` would reject.
+    pub fn validate(&self) -> Result<()> {
+        self.cert_resolver()?;
+        self.client_auth.verifier()?;
+        self.transport.transport_config()?;
+        self.validate_limits()?;
+        if let Some(path) = &self.readiness_check_path {
+            anyhow::ensure!(
+                path.starts_with('/'),
+                "readiness check path {path:?} must start with /"
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_limits(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.max_sessions_per_connection > 0,
+            "max_sessions_per_connection must be at least 1"
+        );
+        self.rate_limits.validate()
+    }
+}
+
+/// Configuration shared by every connection accepted by the endpoint.
+pub(crate) struct ConnectionConfig {
+    pub(crate) router: Router,
+    pub(crate) max_sessions: usize,
+    pub(crate) site: Option<LeptosOptions>,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) allowed_origins: Vec<AllowedOrigin>,
+    pub(crate) token_auth: Option<Arc<TokenAuth>>,
+    pub(crate) rate_limits: RateLimitOpt,
+    pub(crate) ip_limits: Arc<IpLimits>,
+    pub(crate) qlog_dir: Option<PathBuf>,
+}
+
+pub async fn start(
+    opt: WebTransportOpt,
+    router: Router,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("WebTransportOpt: {opt:#?}");
+    info!("WebTransport routes: {router:?}");
+    let max_sessions = opt.max_sessions_per_connection;
+    let config = Arc::new(ConnectionConfig {
+        router,
+        max_sessions: max_sessions as usize,
+        site: opt.site.clone(),
+        shutdown: shutdown.clone(),
+        metrics: Arc::new(Metrics::new()),
+        allowed_origins: opt.allowed_origins.clone(),
+        token_auth: opt.token_auth.clone(),
+        rate_limits: opt.rate_limits.clone(),
+        ip_limits: Arc::new(IpLimits::new(&opt.rate_limits)),
+        qlog_dir: opt.qlog_dir.clone(),
+    });
+
+    // The health server starts first, so probes can tell a failed endpoint from a slow one.
+    let health = Arc::new(Health::new(
+        shutdown.clone(),
+        opt.readiness_check_path.clone(),
+        opt.allowed_origins.first().map(AllowedOrigin::example),
+        opt.token_auth.clone(),
+    ));
+    let health_listen = opt.health_listen.clone();
+    let health_shutdown = shutdown.clone();
+    let health_state = health.clone();
+    let metrics = config.metrics.clone();
+    let _health_task = actix_rt::spawn(async move {
+        async fn health_response() -> impl Responder {
+            HttpResponse::Ok().body("OK")
+        }
+
+        async fn liveness(health: web::Data<Health>) -> impl Responder {
+            match health.live() {
+                Ok(()) => HttpResponse::Ok().body("OK"),
+                Err(err) => HttpResponse::ServiceUnavailable().body(format!("{err:#}")),
+            }
+        }
+
+        async fn readiness(health: web::Data<Health>) -> impl Responder {
+            match health.ready().await {
+                Ok(()) => HttpResponse::Ok().body("OK"),
+                Err(err) => HttpResponse::ServiceUnavailable().body(format!("{err:#}")),
+            }
+        }
+
+        // Expiry of the served certificate in unix time, so monitoring can alert before it lapses.
+        async fn cert_expiry(health: web::Data<Health>) -> impl Responder {
+            let Some(resolver) = health.cert_resolver() else {
+                return HttpResponse::ServiceUnavailable().finish();
+            };
+            match resolver
+                .not_after()
+                .and_then(|not_after| not_after.duration_since(UNIX_EPOCH).ok())
+            {
+                Some(not_after) => HttpResponse::Ok().body(not_after.as_secs().to_string()),
+                None => HttpResponse::NotFound().finish(),
+            }
+        }
+
+        // Lets a page served over TCP pass the hash to `serverCertificateHashes`, which is why
+        // any origin may read it.
+        async fn cert_hash(health: web::Data<Health>) -> impl Responder {
+            let Some(resolver) = health.cert_resolver() else {
+                return HttpResponse::ServiceUnavailable().finish();
+            };
+            HttpResponse::Ok()
+                .insert_header(("Access-Control-Allow-Origin", "*"))
+                .body(tls::hex(&resolver.certificate_hash()))
+        }
+
+        async fn metrics_response(metrics: web::Data<Metrics>) -> impl Responder {
+            HttpResponse::Ok()
+                .content_type(prometheus::TEXT_FORMAT)
+                .body(metrics.render())
+        }
+
+        info!("Starting health server on {}", health_listen);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::from(health_state.clone()))
+                .app_data(web::Data::from(metrics.clone()))
+                .route("/healthz", web::get().to(health_response))
+                .route("/livez", web::get().to(liveness))
+                .route("/readyz", web::get().to(readiness))
+                .route("/metrics", web::get().to(metrics_response))
+                .route("/certz", web::get().to(cert_expiry))
+                .route("/certificate-hash", web::get().to(cert_hash))
+        })
+        .bind(health_listen)
+        .unwrap()
+        .disable_signals()
+        .run();
+        let handle = server.handle();
+        actix_rt::spawn(async move {
+            health_shutdown.wait().await;
+            handle.stop(true).await;
+        });
+        let _ = server.await;
+        info!("Health server stopped");
+    });
+
+    if let Err(err) = serve(opt, config, &health).await {
+        health.failed(&err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+async fn serve(opt: WebTransportOpt, config: Arc<ConnectionConfig>, health: &Health) -> Result<()> {
+    let shutdown = config.shutdown.clone();
+    let max_sessions = opt.max_sessions_per_connection;
+    opt.validate_limits()?;
+    let cert_resolver = Arc::new(opt.cert_resolver()?);
+    cert_resolver
+        .clone()
+        .watch(opt.cert_reload_interval, shutdown.clone());
+
+    let mut tls_config = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_client_cert_verifier(opt.client_auth.verifier()?)
+        .with_cert_resolver(cert_resolver.clone());
+
+    // quinn only takes 0 or u32::MAX, anything else panics.
+    tls_config.max_early_data_size = if opt.zero_rtt { u32::MAX } else { 0 };
+    let alpn: Vec<Vec<u8>> = vec![
+        b"h3".to_vec(),
+        b"h3-32".to_vec(),
+        b"h3-31".to_vec(),
+        b"h3-30".to_vec(),
+        b"h3-29".to_vec(),
+    ];
+    tls_config.alpn_protocols = alpn;
+    if let Some(path) = &opt.key_log_file {
+        tls_config.key_log = Arc::new(KeyLogFile::open(path)?);
+        warn!(
+            "TLS secrets are being written to {}. Anyone who can read that file can decrypt all \
+             QUIC traffic of this server. Never enable SSLKEYLOGFILE in production.",
+            path.display()
+        );
+    }
+    if let Some(dir) = &opt.qlog_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create qlog directory {}", dir.display()))?;
+        warn!(
+            "Writing a qlog event log of every connection to {}",
+            dir.display()
+        );
+    }
+    health.tls_loaded(cert_resolver);
+
+    // 1. create quinn server endpoint and bind UDP socket
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_config));
+    server_config.transport = Arc::new(opt.transport.transport_config()?);
+    let endpoint = quinn::Endpoint::server(server_config, opt.listen)?;
+    health.endpoint_bound(endpoint.local_addr()?);
+
+    info!("listening on {}", opt.listen);
+    if opt.zero_rtt {
+        info!("Accepting 0-RTT data, handlers that are not idempotent wait for the handshake");
+    }
+    let zero_rtt = opt.zero_rtt;
+
+    // 2. Accept new quic connections and spawn a new task to handle them
+    loop {
+        let new_conn = tokio::select! {
+            new_conn = endpoint.accept() => match new_conn {
+                Some(new_conn) => new_conn,
+                None => break,
+            },
+            _ = shutdown.wait() => break,
+        };
+        let ip = new_conn.remote_address().ip();
+        // Dropping the handshake closes it.
+        let Some(handshake) = config.ip_limits.handshake(ip) else {
+            info!("Refusing handshake from {ip}, too many in progress");
+            config.metrics.rate_limited("handshake");
+            continue;
+        };
+        let config = config.clone();
+        // The id is only known once the connection is accepted.
+        let span = info_span!(
+            "connection",
+            conn_id = field::Empty,
+            remote_addr = %new_conn.remote_address()
+        );
+        let handle = async move {
+            // With 0-RTT the connection is accepted as soon as the ClientHello is processed, and
+            // from then on counts against the connection limit instead. The ClientHello may
+            // span several packets, so waiting for the handshake data tells when it was.
+            let mut new_conn = new_conn;
+            let accepted = if zero_rtt && new_conn.handshake_data().await.is_ok() {
+                new_conn.into_0rtt()
+            } else {
+                Err(new_conn)
+            };
+            let (conn, zero_rtt_accepted) = match accepted {
+                Ok((conn, accepted)) => (conn, Some(accepted)),
+                Err(new_conn) => match new_conn.await {
+                    Ok(conn) => (conn, None),
+                    Err(err) => {
+                        config.metrics.handshake_failed();
+                        error!("accepting connection failed: {:?}", err);
+                        return;
+                    }
+                },
+            };
+            drop(handshake);
+            let Some(permit) = config.ip_limits.connection(ip) else {
+                info!("Closing connection from {ip}, too many open");
+                config.metrics.rate_limited("connection");
+                conn.close(EXCESSIVE_LOAD_CLOSE_CODE, b"too many connections");
+                return;
+            };
+            let closed = conn.clone();
+            tokio::spawn(async move {
+                closed.closed().await;
+                drop(permit);
+            });
+            Span::current().record("conn_id", conn.stable_id());
+            info!("new http3 established");
+            config.metrics.connection_opened(&conn);
+            let qlog = match &config.qlog_dir {
+                Some(dir) => ConnectionLog::start(dir, &conn, zero_rtt_accepted.is_some())
+                    .await
+                    .map_err(|err| error!("{err:#}"))
+                    .ok(),
+                None => None,
+            };
+            let handshake = match zero_rtt_accepted {
+                Some(zero_rtt_accepted) => {
+                    let (handshake, done) = Handshake::early(conn.clone(), zero_rtt_accepted);
+                    let metrics = config.metrics.clone();
+                    let qlog = qlog.clone();
+                    let done = async move {
+                        if !done.await {
+                            info!("Connection closed before its handshake completed");
+                            metrics.handshake_failed();
+                            return;
+                        }
+                        metrics.handshake_completed();
+                        if let Some(qlog) = qlog {
+                            qlog.handshake_complete();
+                        }
+                    };
+                    tokio::spawn(done.in_current_span());
+                    handshake
+                }
+                None => {
+                    config.metrics.handshake_completed();
+                    Handshake::complete()
+                }
+            };
+            let (http3, streams) = demux::split(&conn);
+            let h3_conn = sec_http3::server::builder()
+                .enable_webtransport(true)
+                .enable_connect(true)
+                .enable_datagram(true)
+                .max_webtransport_sessions(max_sessions)
+                .send_grease(true)
+                .build(http3)
+                .await
+                .unwrap();
+
+            let handled = handle_connection(h3_conn, streams, conn, config, handshake, qlog).await;
+            if let Err(err) = handled {
+                error!("Failed to handle connection: {err:?}");
+            }
+        };
+        tokio::spawn(handle.instrument(span));
+    }
+
+    // shut down gracefully
+    // stop accepting, then wait for connections to be closed before exiting
+    info!(
+        "Shutting down, draining connections for up to {:?}",
+        opt.shutdown_grace_period
+    );
+    endpoint.set_server_config(None);
+    if tokio::time::timeout(opt.shutdown_grace_period, endpoint.wait_idle())
+        .await
+        .is_err()
+    {
+        info!("Grace period elapsed, closing remaining connections");
+        endpoint.close(SHUTDOWN_CLOSE_CODE, SHUTDOWN_CLOSE_REASON);
+        endpoint.wait_idle().await;
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(
+    mut conn: Connection<Http3Connection, Bytes>,
+    streams: WebTransportStreams,
+    quic_conn: quinn::Connection,
+    config: Arc<ConnectionConfig>,
+    handshake: Handshake,
+    qlog: Option<Arc<ConnectionLog>>,
+) -> Result<()> {
+    // WebTransport CONNECT requests go to the `SessionDispatcher`, which keeps the stream of
+    // every session it accepts for as long as the session lasts.
+    let (requests, requests_rx) = mpsc::unbounded_channel();
+    let dispatcher = SessionDispatcher::new(
+        quic_conn,
+        requests_rx,
+        streams,
+        config.clone(),
+        handshake,
+        qlog,
+    );
+    tokio::spawn(dispatcher.run().in_current_span());
+    let mut draining = false;
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept() => accepted,
+            _ = config.shutdown.wait(), if !draining => {
+                info!("Sending GOAWAY");
+                draining = true;
+                if let Err(err) = conn.shutdown(0).await {
+                    error!("Failed to send GOAWAY: {err:?}");
+                }
+                continue;
+            }
+        };
+        match accepted {
+            Ok(Some((req, stream))) => {
+                info!("new request: {} {}", req.method(), req.uri().path());
+                let ext = req.extensions();
+                match req.method() {
+                    &Method::CONNECT if ext.get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT) => {
+                        // Fails only once the dispatcher ended with the connection.
+                        let _ = requests.send((req, stream));
+                    }
+                    _ => {
+                        let config = config.clone();
+                        tokio::spawn(
+                            async move {
+                                if let Err(err) =
+                                    site::serve_request(req, stream, config.site.as_ref()).await
+                                {
+                                    error!("Failed to serve request: {err:?}");
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                }
+            }
+
+            Ok(None) => {
+                break;
+            }
+
+            Err(err) => {
+                error!("Error on accept {}", err);
+                match err.get_error_level() {
+                    ErrorLevel::ConnectionError => break,
+                    ErrorLevel::StreamError => continue,
+                }
+            }
+        }
+    }
+    Ok(())
+}
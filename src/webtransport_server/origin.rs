@@ -0,0 +1,232 @@
+use super::ConnectionConfig;
+use anyhow::{bail, Context, Result};
+use http::Request;
+use std::str::FromStr;
+use tracing::warn;
+
+/// An origin allowed to open WebTransport sessions, such as `https://example.com` or
+/// `https://*.example.com`.
+///
+/// A wildcard matches any subdomain, at any depth, but not the domain itself. The port must
+/// match too, and defaults to the one implied by the scheme.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedOrigin {
+    scheme: String,
+    host: Host,
+    port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Host {
+    Exact(String),
+    // Holds the parent domain with its leading dot, e.g. `.example.com`.
+    Subdomains(String),
+}
+
+impl FromStr for AllowedOrigin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (scheme, host, port) =
+            parse_origin(s).with_context(|| format!("invalid allowed origin {s:?}"))?;
+        let name = host.strip_prefix("*.").unwrap_or(&host);
+        if name.is_empty() || name.contains('*') {
+            bail!("invalid allowed origin {s:?}, a wildcard must be the first label");
+        }
+        let host = if name.len() < host.len() {
+            Host::Subdomains(format!(".{name}"))
+        } else {
+            Host::Exact(name.to_string())
+        };
+        Ok(Self { scheme, host, port })
+    }
+}
+
+impl AllowedOrigin {
+    fn matches(&self, scheme: &str, host: &str, port: u16) -> bool {
+        self.scheme == scheme
+            && self.port == port
+            && match &self.host {
+                Host::Exact(allowed) => allowed == host,
+                Host::Subdomains(parent) => host.len() > parent.len() && host.ends_with(parent),
+            }
+    }
+
+    /// An origin this entry allows, for requests the server makes to itself.
+    pub(crate) fn example(&self) -> String {
+        let host = match &self.host {
+            Host::Exact(host) => host.clone(),
+            Host::Subdomains(parent) => format!("readyz{parent}"),
+        };
+        match (self.scheme.as_str(), self.port) {
+            ("https", 443) | ("http", 80) => format!("{}://{host}", self.scheme),
+            (scheme, port) => format!("{scheme}://{host}:{port}"),
+        }
+    }
+}
+
+/// Checks the `Origin` of a CONNECT request against `allowed_origins`, logging and counting
+/// rejections. Every origin is allowed when the list is empty.
+pub(crate) fn is_allowed(config: &ConnectionConfig, req: &Request<()>) -> bool {
+    if config.allowed_origins.is_empty() {
+        return true;
+    }
+    let Some(origin) = req.headers().get(http::header::ORIGIN) else {
+        warn!(
+            "Rejecting WebTransport session to {}, no Origin",
+            req.uri().path()
+        );
+        config.metrics.origin_rejected("missing");
+        return false;
+    };
+    let allowed = origin
+        .to_str()
+        .is_ok_and(|origin| allows(&config.allowed_origins, origin));
+    if !allowed {
+        warn!(
+            "Rejecting WebTransport session to {} from origin {:?}",
+            req.uri().path(),
+            origin
+        );
+        config.metrics.origin_rejected("disallowed");
+    }
+    allowed
+}
+
+fn allows(allowed_origins: &[AllowedOrigin], origin: &str) -> bool {
+    parse_origin(origin).is_ok_and(|(scheme, host, port)| {
+        allowed_origins
+            .iter()
+            .any(|allowed| allowed.matches(&scheme, &host, port))
+    })
+}
+
+// Splits `scheme://host[:port]` into its lower-cased parts. Browsers send `null` for opaque
+// origins, which fails here and is therefore never allowed.
+fn parse_origin(origin: &str) -> Result<(String, String, u16)> {
+    let Some((scheme, authority)) = origin.split_once("://") else {
+        bail!("expected scheme://host[:port]");
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    let authority = authority.strip_suffix('/').unwrap_or(authority);
+    if authority.is_empty() || authority.contains(['/', '?', '#', '@']) {
+        bail!("expected scheme://host[:port]");
+    }
+    // The closing bracket of an IPv6 literal comes after any colon inside it.
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, Some(port.parse::<u16>().context("invalid port")?))
+        }
+        _ => (authority, None),
+    };
+    let port = match (port, scheme.as_str()) {
+        (Some(port), _) => port,
+        (None, "https") => 443,
+        (None, "http") => 80,
+        (None, _) => bail!("no default port for scheme {scheme}"),
+    };
+    Ok((scheme, host.to_ascii_lowercase(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(origins: &[&str]) -> Vec<AllowedOrigin> {
+        origins
+            .iter()
+            .map(|origin| origin.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_origins_with_default_ports() {
+        let parse = |origin| parse_origin(origin).unwrap();
+        assert_eq!(
+            parse("https://Example.COM"),
+            ("https".into(), "example.com".into(), 443)
+        );
+        assert_eq!(
+            parse("HTTP://example.com/"),
+            ("http".into(), "example.com".into(), 80)
+        );
+        assert_eq!(
+            parse("https://[::1]:8443"),
+            ("https".into(), "[::1]".into(), 8443)
+        );
+        assert_eq!(
+            parse("https://[::1]"),
+            ("https".into(), "[::1]".into(), 443)
+        );
+        for invalid in [
+            "null",
+            "example.com",
+            "https://",
+            "https://example.com/path",
+            "https://user@example.com",
+            "https://example.com:https",
+            "https://example.com:65536",
+            "ftp://example.com",
+        ] {
+            assert!(parse_origin(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn wildcards_must_lead() {
+        assert!("https://*.example.com".parse::<AllowedOrigin>().is_ok());
+        assert!("https://*".parse::<AllowedOrigin>().is_err());
+        assert!("https://a.*.example.com".parse::<AllowedOrigin>().is_err());
+        assert!("https://*.*.example.com".parse::<AllowedOrigin>().is_err());
+    }
+
+    #[test]
+    fn scheme_and_port_must_match() {
+        let allowed = origins(&["https://example.com", "http://localhost:3000"]);
+        assert!(allows(&allowed, "https://example.com"));
+        assert!(allows(&allowed, "https://example.com:443"));
+        assert!(allows(&allowed, "http://localhost:3000"));
+
+        assert!(!allows(&allowed, "http://example.com"));
+        assert!(!allows(&allowed, "https://example.com:8443"));
+        assert!(!allows(&allowed, "https://localhost:3000"));
+        assert!(!allows(&allowed, "http://localhost"));
+        assert!(!allows(&allowed, "http://localhost:3001"));
+        assert!(!allows(&allowed, "null"));
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let allowed = origins(&["https://*.example.com:8443"]);
+        assert!(allows(&allowed, "https://a.example.com:8443"));
+        assert!(allows(&allowed, "https://a.b.example.com:8443"));
+        assert!(!allows(&allowed, "https://example.com:8443"));
+        assert!(!allows(&allowed, "https://badexample.com:8443"));
+        assert!(!allows(&allowed, "https://a.example.com"));
+        assert!(!allows(&allowed, "http://a.example.com:8443"));
+    }
+
+    // The readiness loopback sends the example of the first allowed origin, which must pass
+    // the same check as browser sessions.
+    #[test]
+    fn examples_are_allowed() {
+        for origin in [
+            "https://example.com",
+            "http://localhost:3000",
+            "https://[::1]:8443",
+            "https://*.example.com",
+            "http://*.example.com:8080",
+        ] {
+            let allowed = origins(&[origin]);
+            let example = allowed[0].example();
+            assert!(
+                allows(&allowed, &example),
+                "{origin} does not allow {example}"
+            );
+        }
+        assert_eq!(
+            origins(&["https://*.example.com"])[0].example(),
+            "https://readyz.example.com"
+        );
+    }
+}
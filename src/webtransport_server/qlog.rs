@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::{error, Instrument};
+
+// How often loss and RTT are sampled from quinn, which is also when the file is flushed.
+const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Event log of one connection in the qlog JSON-SEQ layout, one JSON object per line: a header
+/// followed by events whose `time` is in milliseconds since the connection was accepted.
+///
+/// Logged are the handshake, stream state changes, datagram sizes, quinn's loss and RTT
+/// figures every second, and how the connection closed.
+///
+/// Events are handed to a writer task over a channel, so logging never waits for the disk.
+pub(crate) struct ConnectionLog {
+    start: Instant,
+    records: mpsc::UnboundedSender<Record>,
+}
+
+enum Record {
+    Line(Value),
+    Flush,
+}
+
+impl ConnectionLog {
+    /// Creates `<dir>/<unix ms>-<connection id>.sqlog` for an accepted connection, and samples
+    /// it until it closes. `early_data` is set when it was accepted before its handshake
+    /// completed, which is then logged by `handshake_complete`.
+    pub(crate) async fn start(
+        dir: &Path,
+        conn: &quinn::Connection,
+        early_data: bool,
+    ) -> Result<Arc<Self>> {
+        let reference_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{reference_time}-{}.sqlog", conn.stable_id()));
+        let file = File::create(&path)
+            .await
+            .with_context(|| format!("failed to create qlog file {}", path.display()))?;
+        let (records, received) = mpsc::unbounded_channel();
+        tokio::spawn(write_records(path, BufWriter::new(file), received).in_current_span());
+        let log = Arc::new(Self {
+            start: Instant::now(),
+            records,
+        });
+        log.write(json!({
+            "qlog_version": "0.3",
+            "qlog_format": "JSON-SEQ",
+            "title": format!("connection {}", conn.stable_id()),
+            "trace": {
+                "vantage_point": { "type": "server" },
+                "common_fields": { "reference_time": reference_time as u64 },
+            },
+        }));
+
+        let handshake = conn
+            .handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok());
+        log.event(
+            "transport:connection_started",
+            json!({
+                "dst_ip": conn.remote_address().ip(),
+                "dst_port": conn.remote_address().port(),
+            }),
+        );
+        log.event(
+            "transport:connection_state_updated",
+            json!({
+                "new": if early_data { "early_write" } else { "handshake_complete" },
+                "server_name": handshake.as_ref().and_then(|data| data.server_name.clone()),
+                "alpn": handshake
+                    .as_ref()
+                    .and_then(|data| data.protocol.as_ref())
+                    .map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+            }),
+        );
+
+        tokio::spawn(log.clone().sample(conn.clone()));
+        Ok(log)
+    }
+
+    pub(crate) fn handshake_complete(&self) {
+        self.event(
+            "transport:connection_state_updated",
+            json!({ "new": "handshake_complete" }),
+        );
+    }
+
+    pub(crate) fn event(&self, name: &str, data: Value) {
+        let time = self.start.elapsed().as_secs_f64() * 1000.0;
+        self.write(json!({ "time": time, "name": name, "data": data }));
+    }
+
+    /// Logs a stream the peer opened, or one of its streams that the handler is done with.
+    /// Sessions are identified by the quarter stream id of their CONNECT stream, as in
+    /// datagrams, and streams by their index.
+    pub(crate) fn stream_state(&self, session: u64, stream_index: u64, uni: bool, new: &str) {
+        // Streams are opened by the client, so the low bits are 0b00 or 0b10.
+        let stream_id = stream_index << 2 | if uni { 0b10 } else { 0b00 };
+        let stream_type = if uni {
+            "unidirectional"
+        } else {
+            "bidirectional"
+        };
+        self.event(
+            "transport:stream_state_updated",
+            json!({
+                "stream_id": stream_id,
+                "stream_type": stream_type,
+                "new": new,
+                "session_id": session << 2,
+            }),
+        );
+    }
+
+    pub(crate) fn datagram(&self, session: u64, direction: &str, length: usize) {
+        self.event(
+            "webtransport:datagram",
+            json!({ "session_id": session << 2, "direction": direction, "length": length }),
+        );
+    }
+
+    // Sending only fails once the writer gave up after an error, which it logged.
+    fn write(&self, record: Value) {
+        let _ = self.records.send(Record::Line(record));
+    }
+
+    fn flush(&self) {
+        let _ = self.records.send(Record::Flush);
+    }
+
+    async fn sample(self: Arc<Self>, conn: quinn::Connection) {
+        let mut interval = tokio::time::interval(METRICS_INTERVAL);
+        let reason = loop {
+            tokio::select! {
+                reason = conn.closed() => break reason,
+                _ = interval.tick() => {
+                    let path = conn.stats().path;
+                    self.event(
+                        "recovery:metrics_updated",
+                        json!({
+                            "smoothed_rtt": path.rtt.as_secs_f64() * 1000.0,
+                            "congestion_window": path.cwnd,
+                            "congestion_events": path.congestion_events,
+                            "packets_sent": path.sent_packets,
+                            "packets_lost": path.lost_packets,
+                            "bytes_lost": path.lost_bytes,
+                        }),
+                    );
+                    self.flush();
+                }
+            }
+        };
+        let owner = match reason {
+            quinn::ConnectionError::LocallyClosed | quinn::ConnectionError::TimedOut => "local",
+            _ => "remote",
+        };
+        self.event(
+            "transport:connection_closed",
+            json!({ "owner": owner, "reason": reason.to_string() }),
+        );
+        self.flush();
+    }
+}
+
+// Writes records until every sender is gone, then flushes what is left.
+async fn write_records(
+    path: PathBuf,
+    mut file: BufWriter<File>,
+    mut records: mpsc::UnboundedReceiver<Record>,
+) {
+    while let Some(record) = records.recv().await {
+        let result = match record {
+            Record::Line(value) => {
+                let mut line = value.to_string();
+                line.push('\n');
+                file.write_all(line.as_bytes()).await
+            }
+            Record::Flush => file.flush().await,
+        };
+        if let Err(err) = result {
+            error!(
+                "Failed to write qlog file {}, giving up: {err}",
+                path.display()
+            );
+            return;
+        }
+    }
+    if let Err(err) = file.flush().await {
+        error!("Failed to flush qlog file {}: {err}", path.display());
+    }
+}
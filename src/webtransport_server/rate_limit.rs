@@ -0,0 +1,549 @@
+use super::metrics::Metrics;
+use anyhow::{bail, ensure, Result};
+use quinn::VarInt;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// WebTransport application error code streams are reset with when they exceed a rate limit
+/// and the action is `LimitAction::Reset`.
+pub const RATE_LIMITED_ERROR_CODE: u32 = 429;
+
+/// WebTransport application error code streams are stopped and reset with when they exceed
+/// the per-stream or per-session byte cap.
+pub const MESSAGE_TOO_LARGE_ERROR_CODE: u32 = 413;
+
+/// Connections beyond the per-IP limit are closed with `H3_EXCESSIVE_LOAD`.
+pub const EXCESSIVE_LOAD_CLOSE_CODE: VarInt = VarInt::from_u32(0x107);
+
+/// What happens to streams that exceed a session's rate limits. Excess datagrams are always
+/// dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Excess streams are dropped without reaching the handler, and stream payload beyond the
+    /// byte rate is delayed until the budget refills.
+    #[default]
+    Drop,
+    /// Excess streams, and streams whose payload exceeds the byte rate, are reset with
+    /// `RATE_LIMITED_ERROR_CODE`.
+    Reset,
+}
+
+impl FromStr for LimitAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(Self::Drop),
+            "reset" => Ok(Self::Reset),
+            _ => bail!("unknown limit action {s:?}, expected drop or reset"),
+        }
+    }
+}
+
+/// Rate and size limits for the public endpoint. Limits left as `None` are not enforced.
+///
+/// Per-session rates are token buckets that hold one second worth of tokens, so a session
+/// may burst up to its rate after being idle. Byte caps count incoming stream payload.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitOpt {
+    pub datagrams_per_sec: Option<u32>,
+    /// Datagram and stream payload bytes per second.
+    pub bytes_per_sec: Option<u64>,
+    /// New streams per second, in both directions.
+    pub streams_per_sec: Option<u32>,
+    pub max_connections_per_ip: Option<usize>,
+    /// QUIC handshakes in progress per source IP.
+    pub max_handshakes_per_ip: Option<usize>,
+    pub action: LimitAction,
+    pub max_stream_bytes: Option<u64>,
+    /// Bytes received across all streams of a session.
+    pub max_session_bytes: Option<u64>,
+    /// Larger incoming datagrams are dropped before they reach the handler.
+    pub max_datagram_size: Option<usize>,
+}
+
+impl RateLimitOpt {
+    pub(crate) fn validate(&self) -> Result<()> {
+        let rates = [
+            self.datagrams_per_sec.map(u64::from),
+            self.bytes_per_sec,
+            self.streams_per_sec.map(u64::from),
+        ];
+        ensure!(
+            !rates.contains(&Some(0)),
+            "rate limits must be at least 1 per second"
+        );
+        Ok(())
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    fn has(&mut self, amount: f64) -> bool {
+        self.refill();
+        self.tokens >= amount
+    }
+
+    fn try_take(&mut self, amount: f64) -> bool {
+        if !self.has(amount) {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    // Takes the tokens even when they are not there yet, and returns how long it takes to
+    // earn them back.
+    fn take_debt(&mut self, amount: f64) -> Duration {
+        self.refill();
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+fn bucket(rate: Option<impl Into<f64>>) -> Option<Mutex<TokenBucket>> {
+    rate.map(|rate| Mutex::new(TokenBucket::new(rate.into())))
+}
+
+/// Why a stream was cut off, see `StreamError::Limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// The session went over its byte rate and the action is `LimitAction::Reset`.
+    Rate,
+    StreamBytes,
+    SessionBytes,
+}
+
+impl LimitExceeded {
+    /// WebTransport application error code to stop and reset the stream with.
+    pub fn error_code(self) -> u32 {
+        match self {
+            Self::Rate => RATE_LIMITED_ERROR_CODE,
+            Self::StreamBytes | Self::SessionBytes => MESSAGE_TOO_LARGE_ERROR_CODE,
+        }
+    }
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Rate => "session byte rate exceeded",
+            Self::StreamBytes => "stream byte cap exceeded",
+            Self::SessionBytes => "session byte cap exceeded",
+        })
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Token buckets and byte caps of one session.
+pub struct SessionLimits {
+    datagrams: Option<Mutex<TokenBucket>>,
+    bytes: Option<Mutex<TokenBucket>>,
+    streams: Option<Mutex<TokenBucket>>,
+    action: LimitAction,
+    max_stream_bytes: Option<u64>,
+    max_session_bytes: Option<u64>,
+    max_datagram_size: Option<usize>,
+    session_bytes: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+/// Counts the payload read from one stream, see `SessionLimits::stream`.
+pub(crate) struct StreamLimits {
+    session: Arc<SessionLimits>,
+    received: u64,
+}
+
+impl SessionLimits {
+    pub(crate) fn new(opt: &RateLimitOpt, metrics: Arc<Metrics>) -> Self {
+        Self {
+            datagrams: bucket(opt.datagrams_per_sec),
+            // Precision beyond 2^53 bytes per second does not matter for a rate.
+            bytes: bucket(opt.bytes_per_sec.map(|rate| rate as f64)),
+            streams: bucket(opt.streams_per_sec),
+            action: opt.action,
+            max_stream_bytes: opt.max_stream_bytes,
+            max_session_bytes: opt.max_session_bytes,
+            max_datagram_size: opt.max_datagram_size,
+            session_bytes: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    pub fn action(&self) -> LimitAction {
+        self.action
+    }
+
+    /// Whether an incoming datagram with `bytes` of payload fits the session's size cap and
+    /// rates.
+    pub(crate) fn allow_datagram(&self, bytes: usize) -> bool {
+        if self.max_datagram_size.is_some_and(|max| bytes > max) {
+            self.metrics.size_limit_exceeded("datagram");
+            return false;
+        }
+        // Both buckets are checked before either is debited, so a datagram dropped for the
+        // byte rate does not use up the datagram rate, and the other way round.
+        let mut datagrams = self.datagrams.as_ref().map(|bucket| bucket.lock().unwrap());
+        let mut byte_rate = self.bytes.as_ref().map(|bucket| bucket.lock().unwrap());
+        let bytes = bytes as f64;
+        let allowed = datagrams
+            .as_deref_mut()
+            .map_or(true, |bucket| bucket.has(1.0))
+            && byte_rate
+                .as_deref_mut()
+                .map_or(true, |bucket| bucket.has(bytes));
+        if !allowed {
+            self.metrics.rate_limited("datagram");
+            return false;
+        }
+        if let Some(bucket) = datagrams.as_deref_mut() {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = byte_rate.as_deref_mut() {
+            bucket.tokens -= bytes;
+        }
+        true
+    }
+
+    /// Whether a new incoming stream fits the session's stream rate.
+    pub(crate) fn allow_stream(&self) -> bool {
+        let allowed = take(&self.streams, 1.0);
+        if !allowed {
+            self.metrics.rate_limited("stream");
+        }
+        allowed
+    }
+
+    /// Starts counting the payload of an incoming stream.
+    pub(crate) fn stream(self: &Arc<Self>) -> StreamLimits {
+        StreamLimits {
+            session: self.clone(),
+            received: 0,
+        }
+    }
+
+    fn charge_rate(&self, bytes: usize) -> Result<Duration, LimitExceeded> {
+        let Some(bucket) = &self.bytes else {
+            return Ok(Duration::ZERO);
+        };
+        let delay = match self.action {
+            LimitAction::Drop => bucket.lock().unwrap().take_debt(bytes as f64),
+            LimitAction::Reset => {
+                if !bucket.lock().unwrap().try_take(bytes as f64) {
+                    self.metrics.rate_limited("stream_bytes");
+                    return Err(LimitExceeded::Rate);
+                }
+                Duration::ZERO
+            }
+        };
+        if !delay.is_zero() {
+            self.metrics.rate_limited("stream_bytes");
+        }
+        Ok(delay)
+    }
+}
+
+impl StreamLimits {
+    /// Charges a chunk read from the stream against the byte caps and the session's byte rate.
+    ///
+    /// Over a cap, or over the rate with `LimitAction::Reset`, this fails and the stream is to
+    /// be stopped with `LimitExceeded::error_code`. Over the rate with `LimitAction::Drop`, it
+    /// returns how long to hold the chunk back until the budget covers it.
+    pub(crate) fn charge(&mut self, bytes: usize) -> Result<Duration, LimitExceeded> {
+        let session = &self.session;
+        let bytes_u64 = bytes as u64;
+        self.received += bytes_u64;
+        if session
+            .max_stream_bytes
+            .is_some_and(|max| self.received > max)
+        {
+            session.metrics.size_limit_exceeded("stream");
+            return Err(LimitExceeded::StreamBytes);
+        }
+        let session_bytes = session
+            .session_bytes
+            .fetch_add(bytes_u64, Ordering::Relaxed)
+            + bytes_u64;
+        if session
+            .max_session_bytes
+            .is_some_and(|max| session_bytes > max)
+        {
+            session.metrics.size_limit_exceeded("session");
+            return Err(LimitExceeded::SessionBytes);
+        }
+        session.charge_rate(bytes)
+    }
+}
+
+fn take(bucket: &Option<Mutex<TokenBucket>>, amount: f64) -> bool {
+    match bucket {
+        Some(bucket) => bucket.lock().unwrap().try_take(amount),
+        None => true,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum IpLimit {
+    Handshakes,
+    Connections,
+}
+
+/// Counts the handshakes and connections of every source IP.
+pub(crate) struct IpLimits {
+    max_handshakes: Option<usize>,
+    max_connections: Option<usize>,
+    counts: Mutex<HashMap<(IpAddr, IpLimit), usize>>,
+}
+
+/// Holds one of the handshakes or connections allowed for an IP until it is dropped.
+pub(crate) struct IpPermit {
+    limits: Arc<IpLimits>,
+    key: (IpAddr, IpLimit),
+}
+
+impl IpLimits {
+    pub(crate) fn new(opt: &RateLimitOpt) -> Self {
+        Self {
+            max_handshakes: opt.max_handshakes_per_ip,
+            max_connections: opt.max_connections_per_ip,
+            counts: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn handshake(self: &Arc<Self>, ip: IpAddr) -> Option<IpPermit> {
+        self.acquire(ip, IpLimit::Handshakes, self.max_handshakes)
+    }
+
+    pub(crate) fn connection(self: &Arc<Self>, ip: IpAddr) -> Option<IpPermit> {
+        self.acquire(ip, IpLimit::Connections, self.max_connections)
+    }
+
+    fn acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        kind: IpLimit,
+        max: Option<usize>,
+    ) -> Option<IpPermit> {
+        let key = (ip, kind);
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.get(&key).copied().unwrap_or(0);
+        if max.is_some_and(|max| count >= max) {
+            return None;
+        }
+        counts.insert(key, count + 1);
+        Some(IpPermit {
+            limits: self.clone(),
+            key,
+        })
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Moves the bucket's clock back, as if `elapsed` had passed since its last refill.
+    fn age(bucket: &mut TokenBucket, elapsed: Duration) {
+        bucket.last -= elapsed;
+    }
+
+    #[test]
+    fn buckets_start_full_and_refill_up_to_their_rate() {
+        let mut bucket = TokenBucket::new(10.0);
+        assert!(bucket.try_take(10.0));
+        assert!(!bucket.try_take(1.0));
+
+        age(&mut bucket, Duration::from_millis(500));
+        assert!(bucket.try_take(4.0));
+        assert!(!bucket.try_take(2.0), "only about 5 tokens were earned");
+
+        age(&mut bucket, Duration::from_secs(60));
+        assert!(bucket.try_take(10.0));
+        assert!(
+            !bucket.try_take(1.0),
+            "an idle bucket holds one second of tokens"
+        );
+    }
+
+    #[test]
+    fn failed_takes_keep_the_tokens() {
+        let mut bucket = TokenBucket::new(10.0);
+        assert!(!bucket.try_take(11.0));
+        assert!(bucket.try_take(10.0));
+    }
+
+    #[test]
+    fn debt_is_paid_back_at_the_rate() {
+        let mut bucket = TokenBucket::new(100.0);
+        assert_eq!(bucket.take_debt(50.0), Duration::ZERO);
+        let delay = bucket.take_debt(100.0);
+        assert!(delay > Duration::from_millis(490) && delay <= Duration::from_millis(500));
+        assert!(!bucket.try_take(1.0));
+        age(&mut bucket, delay + Duration::from_millis(10));
+        assert!(bucket.has(0.0));
+    }
+
+    fn limits(opt: RateLimitOpt) -> SessionLimits {
+        SessionLimits::new(&opt, Arc::new(Metrics::new()))
+    }
+
+    #[test]
+    fn datagrams_rejected_for_bytes_keep_the_datagram_rate() {
+        let limits = limits(RateLimitOpt {
+            datagrams_per_sec: Some(2),
+            bytes_per_sec: Some(100),
+            ..Default::default()
+        });
+        assert!(!limits.allow_datagram(101));
+        assert!(!limits.allow_datagram(200));
+        assert!(limits.allow_datagram(10));
+        assert!(limits.allow_datagram(10));
+        assert!(!limits.allow_datagram(10), "the datagram rate is used up");
+    }
+
+    #[test]
+    fn datagrams_rejected_for_count_keep_the_byte_rate() {
+        let limits = limits(RateLimitOpt {
+            datagrams_per_sec: Some(1),
+            bytes_per_sec: Some(100),
+            ..Default::default()
+        });
+        assert!(limits.allow_datagram(1));
+        for _ in 0..10 {
+            assert!(!limits.allow_datagram(50));
+        }
+        limits.datagrams.as_ref().unwrap().lock().unwrap().tokens = 1.0;
+        assert!(
+            limits.allow_datagram(99),
+            "rejected datagrams spent no bytes"
+        );
+    }
+
+    #[test]
+    fn oversized_datagrams_are_rejected_before_the_rates() {
+        let limits = limits(RateLimitOpt {
+            datagrams_per_sec: Some(1),
+            max_datagram_size: Some(1200),
+            ..Default::default()
+        });
+        assert!(!limits.allow_datagram(1201));
+        assert!(limits.allow_datagram(1200));
+    }
+
+    #[test]
+    fn stream_rate() {
+        let limits = limits(RateLimitOpt {
+            streams_per_sec: Some(2),
+            ..Default::default()
+        });
+        assert!(limits.allow_stream());
+        assert!(limits.allow_stream());
+        assert!(!limits.allow_stream());
+        assert!(self::limits(RateLimitOpt::default()).allow_stream());
+    }
+
+    #[test]
+    fn stream_and_session_byte_caps() {
+        let limits = Arc::new(limits(RateLimitOpt {
+            max_stream_bytes: Some(100),
+            max_session_bytes: Some(150),
+            ..Default::default()
+        }));
+        let mut first = limits.stream();
+        assert_eq!(first.charge(60), Ok(Duration::ZERO));
+        assert_eq!(first.charge(40), Ok(Duration::ZERO));
+        assert_eq!(first.charge(1), Err(LimitExceeded::StreamBytes));
+
+        let mut second = limits.stream();
+        assert_eq!(second.charge(40), Ok(Duration::ZERO));
+        assert_eq!(second.charge(10), Err(LimitExceeded::SessionBytes));
+    }
+
+    #[test]
+    fn stream_byte_rate_delays_or_cuts_off() {
+        let opt = RateLimitOpt {
+            bytes_per_sec: Some(100),
+            ..Default::default()
+        };
+        let delayed = Arc::new(limits(opt.clone()));
+        let mut stream = delayed.stream();
+        assert_eq!(stream.charge(100), Ok(Duration::ZERO));
+        let delay = stream.charge(50).unwrap();
+        assert!(delay > Duration::from_millis(490) && delay <= Duration::from_millis(500));
+
+        let reset = Arc::new(limits(RateLimitOpt {
+            action: LimitAction::Reset,
+            ..opt
+        }));
+        let mut stream = reset.stream();
+        assert_eq!(stream.charge(100), Ok(Duration::ZERO));
+        assert_eq!(stream.charge(1), Err(LimitExceeded::Rate));
+    }
+
+    #[test]
+    fn zero_rates_are_invalid() {
+        let opt = RateLimitOpt {
+            bytes_per_sec: Some(0),
+            ..Default::default()
+        };
+        assert!(opt.validate().is_err());
+        assert!(RateLimitOpt::default().validate().is_ok());
+    }
+
+    #[test]
+    fn ip_permits_are_released_on_drop() {
+        let limits = Arc::new(IpLimits::new(&RateLimitOpt {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        }));
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let permit = limits.connection(ip).unwrap();
+        assert!(limits.connection(ip).is_none());
+        assert!(limits.connection(IpAddr::from([192, 0, 2, 2])).is_some());
+        assert!(limits.handshake(ip).is_some(), "handshakes are unlimited");
+        drop(permit);
+        assert!(limits.connection(ip).is_some());
+        assert!(limits.counts.lock().unwrap().is_empty());
+    }
+}
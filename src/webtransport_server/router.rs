@@ -0,0 +1,255 @@
+use super::auth::Claims;
+use super::handler::{AppState, SessionHandler};
+use http::Uri;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Maps the `:path` of a WebTransport CONNECT request to a `SessionHandler`.
+///
+/// Patterns follow the actix syntax: `/chat/{room}` captures a single segment and
+/// `/files/{tail:.*}` captures the rest of the path. Other regex constraints are not
+/// supported. Routes are matched in the order they were registered.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    state: AppState,
+}
+
+struct Route {
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Arc<dyn SessionHandler>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Tail(String),
+}
+
+/// Path parameters, query string and token claims of the request that opened a session.
+#[derive(Debug, Clone, Default)]
+pub struct RequestParams {
+    path: String,
+    params: HashMap<String, String>,
+    query: Vec<(String, String)>,
+    claims: Option<Claims>,
+}
+
+impl RequestParams {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Returns the first query string value for `name`.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_pairs(&self) -> &[(String, String)] {
+        &self.query
+    }
+
+    /// Claims of the verified token, when token auth is configured.
+    pub fn claims(&self) -> Option<&Claims> {
+        self.claims.as_ref()
+    }
+
+    pub(crate) fn set_claims(&mut self, claims: Claims) {
+        self.claims = Some(claims);
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics when a segment of `pattern` has a regex constraint other than `.*`, which would
+    /// otherwise match more paths than it says.
+    pub fn route<H: SessionHandler>(mut self, pattern: &str, handler: H) -> Self {
+        self.routes.push(Route {
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    /// Adds a value to the state shared by every session, see `SessionContext::state`.
+    pub fn app_data<T: Send + Sync + 'static>(mut self, data: T) -> Self {
+        self.state = self.state.insert(data);
+        self
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    pub(crate) fn recognize(&self, uri: &Uri) -> Option<(Arc<dyn SessionHandler>, RequestParams)> {
+        let path = uri.path();
+        let (handler, params) = self
+            .routes
+            .iter()
+            .find_map(|route| Some((route.handler.clone(), route.matches(path)?)))?;
+        let query = uri
+            .query()
+            .map(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some((
+            handler,
+            RequestParams {
+                path: path.to_string(),
+                params,
+                query,
+                claims: None,
+            },
+        ))
+    }
+}
+
+impl std::fmt::Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.routes.iter().map(|route| &route.pattern))
+            .finish()
+    }
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        let mut rest = path.trim_start_matches('/');
+        for segment in &self.segments {
+            if let Segment::Tail(name) = segment {
+                params.insert(name.clone(), decode(rest)?);
+                return Some(params);
+            }
+            if rest.is_empty() {
+                return None;
+            }
+            let (current, next) = rest.split_once('/').unwrap_or((rest, ""));
+            match segment {
+                Segment::Static(expected) if decode(current)? == *expected => {}
+                Segment::Param(name) => {
+                    params.insert(name.clone(), decode(current)?);
+                }
+                _ => return None,
+            }
+            rest = next;
+        }
+        rest.trim_end_matches('/').is_empty().then_some(params)
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            match segment
+                .strip_prefix('{')
+                .and_then(|segment| segment.strip_suffix('}'))
+            {
+                Some(param) => match param.split_once(':') {
+                    Some((name, ".*")) => Segment::Tail(name.to_string()),
+                    Some((name, regex)) => panic!(
+                        "route {pattern:?}: regex constraint {regex:?} of {{{name}}} is not \
+                         supported, only {{{name}:.*}} is"
+                    ),
+                    None => Segment::Param(param.to_string()),
+                },
+                None => Segment::Static(segment.to_string()),
+            }
+        })
+        .collect()
+}
+
+fn decode(segment: &str) -> Option<String> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .ok()
+        .map(|segment| segment.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webtransport_server::EchoHandler;
+
+    fn route(pattern: &str) -> Route {
+        Route {
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+            handler: Arc::new(EchoHandler),
+        }
+    }
+
+    #[test]
+    fn static_routes_match_exactly() {
+        let route = route("/echo");
+        assert!(route.matches("/echo").is_some());
+        assert!(route.matches("/echo/").is_some());
+        assert!(route.matches("/").is_none());
+        assert!(route.matches("/echo/more").is_none());
+        assert!(route.matches("/ech").is_none());
+    }
+
+    #[test]
+    fn params_capture_one_decoded_segment() {
+        let route = route("/chat/{room}");
+        let params = route.matches("/chat/caf%C3%A9").unwrap();
+        assert_eq!(params["room"], "café");
+        assert!(route.matches("/chat").is_none());
+        assert!(route.matches("/chat/a/b").is_none());
+        assert!(route.matches("/chat/%FF").is_none());
+    }
+
+    #[test]
+    fn tails_capture_the_rest_of_the_path() {
+        let route = route("/files/{tail:.*}");
+        assert_eq!(route.matches("/files/a/b.txt").unwrap()["tail"], "a/b.txt");
+        assert_eq!(route.matches("/files").unwrap()["tail"], "");
+        assert!(route.matches("/other/a").is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "regex constraint \"\\\\d+\" of {id} is not supported")]
+    fn regex_constraints_are_rejected() {
+        let _ = Router::new().route("/users/{id:\\d+}", EchoHandler);
+    }
+
+    #[test]
+    fn routes_are_tried_in_registration_order() {
+        let router = Router::new()
+            .route("/rooms/lobby", EchoHandler)
+            .route("/rooms/{room}", EchoHandler);
+        let uri: Uri = "/rooms/lobby?user=a&user=b&x=%20y".parse().unwrap();
+        let (_, params) = router.recognize(&uri).unwrap();
+        assert_eq!(params.param("room"), None);
+        assert_eq!(params.query("user"), Some("a"));
+        assert_eq!(params.query("x"), Some(" y"));
+        assert_eq!(params.query_pairs().len(), 3);
+
+        let (_, params) = router.recognize(&"/rooms/42".parse().unwrap()).unwrap();
+        assert_eq!(params.param("room"), Some("42"));
+        assert!(router.recognize(&"/nope".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,552 @@
+use super::close::{watch_connect_stream, SessionEnd, OPEN_FAILED_CLOSE_CODE};
+use super::demux::{
+    QuicRecvStream, QuicSendStream, RequestBidiStream, StreamOpener, WebTransportStream,
+    WebTransportStreams,
+};
+use super::handler::{ConnectionInfo, SessionContext, SessionHandler};
+use super::handshake::Handshake;
+use super::metrics::{self, Metrics};
+use super::qlog::ConnectionLog;
+use super::rate_limit::{LimitAction, RATE_LIMITED_ERROR_CODE};
+use super::router::RequestParams;
+use super::shutdown::drain_capsule;
+use super::stream::{RawRecvStream, RawSendStream};
+use super::{auth, origin, ConnectionConfig};
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::join_all;
+use http::{Request, Response, StatusCode};
+use sec_http3::quic::{RecvStream as _, SendStream as _};
+use sec_http3::sec_http3_quinn as h3_quinn;
+use sec_http3::webtransport::SessionId;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+pub(crate) type RequestStream = sec_http3::server::RequestStream<RequestBidiStream, Bytes>;
+pub(crate) type ConnectSendStream = sec_http3::server::RequestStream<QuicSendStream, Bytes>;
+pub(crate) type ConnectRecvStream = sec_http3::server::RequestStream<QuicRecvStream, Bytes>;
+
+/// Reports the end of a session to the dispatcher, which owns the CONNECT streams. Carries
+/// `SessionContext::close` requests as well as what `watch_connect_stream` sees.
+pub(crate) type EndSender = mpsc::UnboundedSender<(SessionId, SessionEnd)>;
+
+// Datagrams are unreliable, so a session that falls behind drops them instead of stalling
+// every other session on the connection.
+const DATAGRAM_QUEUE_SIZE: usize = 1024;
+
+/// Sends HTTP/3 datagrams for one session directly on the QUIC connection, prefixed with the
+/// index of the session's CONNECT stream.
+#[derive(Clone)]
+pub(crate) struct DatagramSender {
+    conn: quinn::Connection,
+    quarter_stream_id: u64,
+    metrics: Arc<Metrics>,
+    qlog: Option<Arc<ConnectionLog>>,
+}
+
+impl DatagramSender {
+    pub(crate) fn new(
+        conn: quinn::Connection,
+        quarter_stream_id: u64,
+        metrics: Arc<Metrics>,
+        qlog: Option<Arc<ConnectionLog>>,
+    ) -> Self {
+        Self {
+            conn,
+            quarter_stream_id,
+            metrics,
+            qlog,
+        }
+    }
+
+    pub(crate) fn connection(&self) -> &quinn::Connection {
+        &self.conn
+    }
+
+    pub(crate) fn send(&self, payload: Bytes) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(8 + payload.len());
+        encode_varint(&mut buf, self.quarter_stream_id);
+        let len = payload.len();
+        buf.put(payload);
+        self.conn.send_datagram(buf.freeze())?;
+        self.metrics.datagram(metrics::OUT);
+        if let Some(qlog) = &self.qlog {
+            qlog.datagram(self.quarter_stream_id, metrics::OUT, len);
+        }
+        Ok(())
+    }
+}
+
+/// Maps a WebTransport application error code onto the HTTP/3 error code space used to reset
+/// streams, as specified by draft-ietf-webtrans-http3.
+pub(crate) fn webtransport_error_code(code: u32) -> u64 {
+    let code = u64::from(code);
+    0x52e4_a40f_a8db + code + code / 0x1e
+}
+
+pub(crate) fn encode_varint(buf: &mut BytesMut, value: u64) {
+    if value < 1 << 6 {
+        buf.put_u8(value as u8);
+    } else if value < 1 << 14 {
+        buf.put_u16(0x4000 | value as u16);
+    } else if value < 1 << 30 {
+        buf.put_u32(0x8000_0000 | value as u32);
+    } else {
+        buf.put_u64(0xc000_0000_0000_0000 | value);
+    }
+}
+
+pub(crate) fn decode_varint(buf: &mut impl Buf) -> Option<u64> {
+    if !buf.has_remaining() {
+        return None;
+    }
+    let len = 1 << (buf.chunk()[0] >> 6);
+    if buf.remaining() < len {
+        return None;
+    }
+    let mut value = u64::from(buf.get_u8() & 0x3f);
+    for _ in 1..len {
+        value = value << 8 | u64::from(buf.get_u8());
+    }
+    Some(value)
+}
+
+struct SessionEntry {
+    ctx: SessionContext,
+    // Parent of the spans of the session task and its streams.
+    span: Span,
+    quarter_stream_id: u64,
+    handler: Arc<dyn SessionHandler>,
+    datagrams: mpsc::Sender<Bytes>,
+    end: oneshot::Sender<SessionEnd>,
+    // The send half of the session's CONNECT stream, closing it would end the session. The
+    // receive half is read by `watch_connect_stream`.
+    connect_stream: ConnectSendStream,
+}
+
+/// Accepts the WebTransport sessions of a connection, and demultiplexes the datagrams and
+/// streams of the connection to them.
+pub(crate) struct SessionDispatcher {
+    conn: quinn::Connection,
+    connection_info: Arc<ConnectionInfo>,
+    config: Arc<ConnectionConfig>,
+    sessions: HashMap<SessionId, SessionEntry>,
+    // CONNECT requests, which the HTTP/3 server accepts.
+    requests: mpsc::UnboundedReceiver<(Request<()>, RequestStream)>,
+    streams: mpsc::UnboundedReceiver<WebTransportStream>,
+    opener: h3_quinn::OpenStreams,
+    // Set once a session was opened in 0-RTT data, which counts the connection as 0-RTT.
+    zero_rtt: bool,
+    qlog: Option<Arc<ConnectionLog>>,
+    end_tx: EndSender,
+    end_rx: mpsc::UnboundedReceiver<(SessionId, SessionEnd)>,
+}
+
+impl SessionDispatcher {
+    pub(crate) fn new(
+        conn: quinn::Connection,
+        requests: mpsc::UnboundedReceiver<(Request<()>, RequestStream)>,
+        streams: WebTransportStreams,
+        config: Arc<ConnectionConfig>,
+        handshake: Handshake,
+        qlog: Option<Arc<ConnectionLog>>,
+    ) -> Self {
+        let (end_tx, end_rx) = mpsc::unbounded_channel();
+        Self {
+            connection_info: Arc::new(ConnectionInfo::new(&conn, handshake)),
+            conn,
+            config,
+            sessions: HashMap::new(),
+            requests,
+            streams: streams.incoming,
+            opener: streams.opener,
+            zero_rtt: false,
+            qlog,
+            end_tx,
+            end_rx,
+        }
+    }
+
+    fn register(
+        &mut self,
+        session_id: SessionId,
+        quarter_stream_id: u64,
+        handler: Arc<dyn SessionHandler>,
+        params: RequestParams,
+        connect_stream: RequestStream,
+    ) {
+        let span = info_span!(
+            "session",
+            session_id = ?session_id,
+            path = params.path(),
+            token_id = field::Empty
+        );
+        if let Some(token_id) = params.claims().and_then(|claims| claims.jti.as_deref()) {
+            span.record("token_id", token_id);
+        }
+        let ctx = SessionContext::new(
+            StreamOpener::new(self.opener.clone(), quarter_stream_id),
+            session_id,
+            DatagramSender::new(
+                self.conn.clone(),
+                quarter_stream_id,
+                self.config.metrics.clone(),
+                self.qlog.clone(),
+            ),
+            self.connection_info.clone(),
+            params,
+            self.end_tx.clone(),
+            &self.config,
+        );
+        let (connect_stream, recv) = connect_stream.split();
+        let conn = self.conn.clone();
+        let end_tx = self.end_tx.clone();
+        let watch = async move {
+            let end = watch_connect_stream(recv, conn).await;
+            let _ = end_tx.send((session_id, end));
+        };
+        tokio::spawn(watch.instrument(span.clone()));
+        self.config.metrics.session_opened(ctx.early_data());
+        if ctx.early_data() && !std::mem::replace(&mut self.zero_rtt, true) {
+            self.config.metrics.zero_rtt_connection();
+        }
+        let (datagrams, rx) = mpsc::channel(DATAGRAM_QUEUE_SIZE);
+        let (end, end_rx) = oneshot::channel();
+        tokio::spawn(
+            run_session(ctx.clone(), handler.clone(), rx, end_rx).instrument(span.clone()),
+        );
+        self.sessions.insert(
+            session_id,
+            SessionEntry {
+                ctx,
+                span,
+                quarter_stream_id,
+                handler,
+                datagrams,
+                end,
+                connect_stream,
+            },
+        );
+        info!(
+            "WebTransport session established {:?}, {} active on connection",
+            session_id,
+            self.sessions.len()
+        );
+    }
+
+    /// Dispatches until the connection ends, and returns how it ended. Sessions that are still
+    /// open end the same way.
+    pub(crate) async fn run(mut self) -> SessionEnd {
+        let end = self.dispatch().await;
+        info!("Finished handling sessions, connection {end}");
+        self.end_all(end.clone()).await;
+        end
+    }
+
+    async fn dispatch(&mut self) -> SessionEnd {
+        let mut draining = false;
+        loop {
+            let config = self.config.clone();
+            let conn = self.conn.clone();
+            tokio::select! {
+                _ = config.shutdown.wait(), if !draining => {
+                    draining = true;
+                    self.drain().await;
+                }
+                Some((session_id, end)) = self.end_rx.recv() => {
+                    self.end_session(session_id, end).await;
+                }
+                reason = conn.closed() => return SessionEnd::from_connection(&reason),
+                datagram = conn.read_datagram() => match datagram {
+                    Ok(datagram) => self.dispatch_datagram(datagram),
+                    Err(reason) => return SessionEnd::from_connection(&reason),
+                },
+                Some(stream) = self.streams.recv() => match stream {
+                    WebTransportStream::Uni(session_id, recv) => {
+                        self.dispatch_uni(session_id, recv);
+                    }
+                    WebTransportStream::Bidi(session_id, send, recv) => {
+                        self.dispatch_bidi(session_id, send, recv);
+                    }
+                },
+                Some((req, stream)) = self.requests.recv() => {
+                    if let Err(err) = self.accept_request(req, stream).await {
+                        error!("Failed to accept request: {err:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tells every session on the connection that the server is going away, with the
+    /// `on_drain` callback and a DRAIN_WEBTRANSPORT_SESSION capsule.
+    async fn drain(&mut self) {
+        info!("Draining {} WebTransport sessions", self.sessions.len());
+        for entry in self.sessions.values() {
+            let handler = entry.handler.clone();
+            let ctx = entry.ctx.clone();
+            tokio::spawn(async move { handler.on_drain(&ctx).await });
+        }
+        // Sent concurrently, so a peer that is slow to take the capsule only delays itself.
+        let capsules = self.sessions.values_mut().map(|entry| async move {
+            if let Err(err) = entry.connect_stream.send_data(drain_capsule()).await {
+                error!("Failed to send drain capsule: {err:?}");
+            }
+        });
+        join_all(capsules).await;
+    }
+
+    /// Ends a session, exactly once: whatever reports the end of a session first removes its
+    /// entry, and dropping the entry ends the session task, which calls `on_close` with `end`.
+    ///
+    /// A session closed locally sends a CLOSE_WEBTRANSPORT_SESSION capsule and finishes its
+    /// CONNECT stream, and a close from the peer is answered with a FIN.
+    async fn end_session(&mut self, session_id: SessionId, end: SessionEnd) {
+        let Some(entry) = self.sessions.remove(&session_id) else {
+            return;
+        };
+        match &end {
+            SessionEnd::ClosedLocally(_) | SessionEnd::Closed(_) => {
+                info!("WebTransport session {session_id:?} {end}");
+            }
+            SessionEnd::Reset(_) | SessionEnd::TransportError(_) => {
+                warn!("WebTransport session {session_id:?} {end}");
+            }
+        }
+        let mut stream = entry.connect_stream;
+        match &end {
+            SessionEnd::ClosedLocally(close) => {
+                let result = async {
+                    stream.send_data(close.capsule()).await?;
+                    stream.finish().await
+                }
+                .await;
+                if let Err(err) = result {
+                    error!("Failed to send close capsule: {err:?}");
+                }
+            }
+            SessionEnd::Closed(_) => {
+                let _ = stream.finish().await;
+            }
+            SessionEnd::Reset(_) | SessionEnd::TransportError(_) => {}
+        }
+        let _ = entry.end.send(end);
+    }
+
+    async fn end_all(&mut self, end: SessionEnd) {
+        let ids: Vec<_> = self.sessions.keys().cloned().collect();
+        for session_id in ids {
+            self.end_session(session_id, end.clone()).await;
+        }
+    }
+
+    fn dispatch_datagram(&self, mut buf: Bytes) {
+        // HTTP/3 datagrams start with the index of the CONNECT stream of their session.
+        let Some(session_id) = decode_varint(&mut buf).and_then(|id| SessionId::try_from(id).ok())
+        else {
+            warn!("Dropping malformed datagram");
+            return;
+        };
+        let Some(entry) = self.sessions.get(&session_id) else {
+            warn!("Dropping datagram for unknown session {:?}", session_id);
+            return;
+        };
+        self.config.metrics.datagram(metrics::IN);
+        if let Some(qlog) = &self.qlog {
+            qlog.datagram(entry.quarter_stream_id, metrics::IN, buf.len());
+        }
+        if !entry.ctx.limits().allow_datagram(buf.len()) {
+            return;
+        }
+        if entry.datagrams.try_send(buf).is_err() {
+            warn!(
+                "Dropping datagram, session {:?} is falling behind",
+                session_id
+            );
+        }
+    }
+
+    fn dispatch_uni(&self, session_id: SessionId, mut stream: RawRecvStream) {
+        let Some(entry) = self.sessions.get(&session_id) else {
+            warn!(
+                "Dropping unidirectional stream for unknown session {:?}",
+                session_id
+            );
+            return;
+        };
+        if !entry.ctx.limits().allow_stream() {
+            warn!("Refusing unidirectional stream, session {session_id:?} is over its stream rate");
+            if entry.ctx.limits().action() == LimitAction::Reset {
+                stream.stop_sending(webtransport_error_code(RATE_LIMITED_ERROR_CODE));
+            }
+            return;
+        }
+        let stream_id = stream.recv_id();
+        let span = info_span!(parent: &entry.span, "stream", stream_id = ?stream_id, kind = "uni");
+        let qlog = self.qlog.clone();
+        let session = entry.quarter_stream_id;
+        if let Some(qlog) = &qlog {
+            qlog.stream_state(session, stream_id.index(), true, "open");
+        }
+        let handler = entry.handler.clone();
+        let ctx = entry.ctx.clone();
+        let stream = ctx.wrap_uni(stream);
+        let handle = async move {
+            let result = if replay_safe(&ctx, handler.as_ref()).await {
+                handler.on_uni_stream(&ctx, stream).await
+            } else {
+                Ok(())
+            };
+            if let Some(qlog) = &qlog {
+                qlog.stream_state(session, stream_id.index(), true, "closed");
+            }
+            if let Err(err) = result {
+                ctx.metrics().handler_error("uni_stream");
+                error!("Error handling unidirectional stream: {err:?}");
+            }
+        };
+        tokio::spawn(handle.instrument(span));
+    }
+
+    fn dispatch_bidi(
+        &self,
+        session_id: SessionId,
+        mut send: RawSendStream,
+        mut recv: RawRecvStream,
+    ) {
+        let Some(entry) = self.sessions.get(&session_id) else {
+            warn!(
+                "Dropping bidirectional stream for unknown session {:?}",
+                session_id
+            );
+            return;
+        };
+        if !entry.ctx.limits().allow_stream() {
+            warn!("Refusing bidirectional stream, session {session_id:?} is over its stream rate");
+            if entry.ctx.limits().action() == LimitAction::Reset {
+                let code = webtransport_error_code(RATE_LIMITED_ERROR_CODE);
+                send.reset(code);
+                recv.stop_sending(code);
+            }
+            return;
+        }
+        let stream_id = recv.recv_id();
+        let span = info_span!(parent: &entry.span, "stream", stream_id = ?stream_id, kind = "bidi");
+        let qlog = self.qlog.clone();
+        let session = entry.quarter_stream_id;
+        if let Some(qlog) = &qlog {
+            qlog.stream_state(session, stream_id.index(), false, "open");
+        }
+        let handler = entry.handler.clone();
+        let ctx = entry.ctx.clone();
+        let (send, recv) = ctx.wrap_bidi(send, recv);
+        let handle = async move {
+            let result = if replay_safe(&ctx, handler.as_ref()).await {
+                handler.on_bidi_stream(&ctx, send, recv).await
+            } else {
+                Ok(())
+            };
+            if let Some(qlog) = &qlog {
+                qlog.stream_state(session, stream_id.index(), false, "closed");
+            }
+            if let Err(err) = result {
+                ctx.metrics().handler_error("bidi_stream");
+                error!("Error handling bidirectional stream: {err:?}");
+            }
+        };
+        tokio::spawn(handle.instrument(span));
+    }
+
+    /// Answers a WebTransport CONNECT request, and registers the session when it is accepted.
+    async fn accept_request(&mut self, req: Request<()>, mut stream: RequestStream) -> Result<()> {
+        if self.config.shutdown.is_triggered() {
+            return reject(stream, StatusCode::SERVICE_UNAVAILABLE).await;
+        }
+        if !origin::is_allowed(&self.config, &req) {
+            return reject(stream, StatusCode::FORBIDDEN).await;
+        }
+        let Some((handler, mut params)) = self.config.router.recognize(req.uri()) else {
+            info!("No WebTransport route for {}", req.uri().path());
+            return reject(stream, StatusCode::NOT_FOUND).await;
+        };
+        if let Err(status) = auth::authorize(&self.config, &req, &mut params) {
+            return reject(stream, status).await;
+        }
+        if self.sessions.len() >= self.config.max_sessions {
+            info!(
+                "Rejecting WebTransport session, limit of {} reached",
+                self.config.max_sessions
+            );
+            return reject(stream, StatusCode::TOO_MANY_REQUESTS).await;
+        }
+        let response = Response::builder()
+            .header("sec-webtransport-http3-draft", "draft02")
+            .status(StatusCode::OK)
+            .body(())
+            .unwrap();
+        stream.send_response(response).await?;
+        let stream_id = stream.id();
+        self.register(
+            SessionId::from(stream_id),
+            stream_id.index(),
+            handler,
+            params,
+            stream,
+        );
+        Ok(())
+    }
+}
+
+async fn reject(mut stream: RequestStream, status: StatusCode) -> Result<()> {
+    let response = Response::builder().status(status).body(()).unwrap();
+    stream.send_response(response).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Holds the callbacks of handlers that are not idempotent back while the connection may still
+/// carry replayed 0-RTT data, and returns whether they may run.
+async fn replay_safe(ctx: &SessionContext, handler: &dyn SessionHandler) -> bool {
+    if handler.idempotent() {
+        return true;
+    }
+    match ctx.replay_safe().await {
+        Ok(()) => true,
+        Err(err) => {
+            info!("Dropping early data, {err}");
+            false
+        }
+    }
+}
+
+async fn run_session(
+    ctx: SessionContext,
+    handler: Arc<dyn SessionHandler>,
+    mut datagrams: mpsc::Receiver<Bytes>,
+    end: oneshot::Receiver<SessionEnd>,
+) {
+    // Without a handshake the connection is gone, and the session ends with it.
+    if replay_safe(&ctx, handler.as_ref()).await {
+        if let Err(err) = handler.on_open(&ctx).await {
+            ctx.metrics().handler_error("open");
+            error!("Failed to open session: {err:?}");
+            ctx.close(OPEN_FAILED_CLOSE_CODE, "failed to open session");
+        } else {
+            while let Some(buf) = datagrams.recv().await {
+                if let Err(err) = handler.on_datagram(&ctx, buf).await {
+                    ctx.metrics().handler_error("datagram");
+                    error!("Error handling datagram: {err:?}");
+                }
+            }
+        }
+    }
+    // Only a dispatcher that went away without ending its sessions drops the sender.
+    let end = end
+        .await
+        .unwrap_or_else(|_| SessionEnd::TransportError("connection handler stopped".to_string()));
+    handler.on_close(&ctx, &end).await;
+    ctx.metrics().session_closed();
+    info!("Finished handling session {:?}", ctx.session_id());
+}
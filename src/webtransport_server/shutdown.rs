@@ -0,0 +1,83 @@
+use super::session::encode_varint;
+use bytes::{Bytes, BytesMut};
+use quinn::VarInt;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Application close code used for connections that are still open when the grace period
+/// runs out. This is `H3_NO_ERROR`, so clients treat it as an orderly shutdown.
+pub const SHUTDOWN_CLOSE_CODE: VarInt = VarInt::from_u32(0x100);
+pub const SHUTDOWN_CLOSE_REASON: &[u8] = b"server shutting down";
+
+// DRAIN_WEBTRANSPORT_SESSION capsule from draft-ietf-webtrans-http3.
+const DRAIN_WEBTRANSPORT_SESSION: u64 = 0x78ae;
+
+/// Shutdown signal shared by the actix servers and the QUIC endpoint.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Resolves once `trigger` has been called.
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        // The sender lives as long as `self`, so this can only fail after a trigger.
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Resolves on SIGTERM (sent by Kubernetes when a pod is stopped) or Ctrl-C.
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl-C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+pub(crate) fn drain_capsule() -> Bytes {
+    let mut buf = BytesMut::with_capacity(4);
+    encode_varint(&mut buf, DRAIN_WEBTRANSPORT_SESSION);
+    encode_varint(&mut buf, 0);
+    buf.freeze()
+}
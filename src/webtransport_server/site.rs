@@ -0,0 +1,133 @@
+use super::session::RequestStream;
+use crate::app::App;
+use anyhow::Result;
+use bytes::Bytes;
+use http::{header, Method, Request, Response, StatusCode};
+use leptos::*;
+use leptos_actix::ResponseOptions;
+use leptos_integration_utils::html_parts_separated;
+use leptos_meta::MetaContext;
+use leptos_router::{RouterIntegrationContext, ServerIntegration};
+use std::path::{Component, Path, PathBuf};
+use tracing::{error, info};
+
+/// Answers a plain HTTP/3 request the same way the actix server in `main.rs` does: `/pkg`,
+/// `/assets` and the favicon come from the site root, every other path is rendered by `App`.
+pub(crate) async fn serve_request(
+    req: Request<()>,
+    mut stream: RequestStream,
+    site: Option<&LeptosOptions>,
+) -> Result<()> {
+    info!(method = %req.method(), path = req.uri().path(), "Received request");
+    let Some(options) = site else {
+        return send(&mut stream, false, StatusCode::NOT_FOUND, "text/plain", "").await;
+    };
+    let head_only = req.method() == Method::HEAD;
+    if req.method() != Method::GET && !head_only {
+        return send(
+            &mut stream,
+            false,
+            StatusCode::METHOD_NOT_ALLOWED,
+            "text/plain",
+            "",
+        )
+        .await;
+    }
+
+    let site_root = Path::new(&options.site_root);
+    let path = req.uri().path();
+    let file = if let Some(file) = path.strip_prefix("/pkg/") {
+        Some(sanitize(file).map(|file| site_root.join("pkg").join(file)))
+    } else if let Some(file) = path.strip_prefix("/assets/") {
+        Some(sanitize(file).map(|file| site_root.join(file)))
+    } else if path == "/favicon.ico" {
+        Some(Some(site_root.join("favicon.ico")))
+    } else {
+        None
+    };
+
+    let Some(file) = file else {
+        let uri = req.uri().to_string();
+        let (status, html) = render_app(options, &uri);
+        return send(
+            &mut stream,
+            head_only,
+            status,
+            "text/html; charset=utf-8",
+            html,
+        )
+        .await;
+    };
+    let Some(file) = file else {
+        return send(&mut stream, false, StatusCode::NOT_FOUND, "text/plain", "").await;
+    };
+    match tokio::fs::read(&file).await {
+        Ok(body) => {
+            let content_type = mime_guess::from_path(&file).first_or_octet_stream();
+            send(
+                &mut stream,
+                head_only,
+                StatusCode::OK,
+                content_type.as_ref(),
+                body,
+            )
+            .await
+        }
+        Err(_) => send(&mut stream, false, StatusCode::NOT_FOUND, "text/plain", "").await,
+    }
+}
+
+// Only plain path segments are allowed so a request can never escape the site root.
+fn sanitize(file: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(file)
+        .decode_utf8()
+        .ok()?;
+    let path = PathBuf::from(decoded.as_ref());
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then_some(path)
+}
+
+fn render_app(options: &LeptosOptions, uri: &str) -> (StatusCode, String) {
+    let runtime = create_runtime();
+    let response_options = ResponseOptions::default();
+    provide_context(RouterIntegrationContext::new(ServerIntegration {
+        path: format!("http://leptos{uri}"),
+    }));
+    provide_context(MetaContext::new());
+    provide_context(response_options.clone());
+
+    let body = leptos::ssr::render_to_string(App).to_string();
+    let meta = use_context::<MetaContext>();
+    let (head, tail) = html_parts_separated(options, meta.as_ref());
+    let head_tags = meta.map(|meta| meta.dehydrate()).unwrap_or_default();
+    let html = format!("{head}{head_tags}</head><body>{body}{tail}");
+    let status = response_options.0.read().status.unwrap_or(StatusCode::OK);
+
+    runtime.dispose();
+    (status, html)
+}
+
+async fn send(
+    stream: &mut RequestStream,
+    head_only: bool,
+    status: StatusCode,
+    content_type: &str,
+    body: impl Into<Bytes>,
+) -> Result<()> {
+    let body = body.into();
+    let response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(())
+        .unwrap();
+    stream.send_response(response).await?;
+    if !head_only && !body.is_empty() {
+        stream.send_data(body).await?;
+    }
+    if let Err(err) = stream.finish().await {
+        error!("Failed to finish HTTP/3 response: {err:?}");
+    }
+    Ok(())
+}
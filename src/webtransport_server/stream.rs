@@ -0,0 +1,289 @@
+use super::demux::{QuicRecvStream, QuicSendStream};
+use super::metrics::Metrics;
+use super::rate_limit::{LimitExceeded, SessionLimits, StreamLimits};
+use super::session::webtransport_error_code;
+use bytes::{Buf, Bytes};
+use sec_http3::quic::{self, RecvStream as _, SendStream as _, SendStreamUnframed as _};
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+use tracing::info;
+
+/// Streams of a session as accepted or opened, past their header, before they are handed to a
+/// handler.
+pub(crate) type RawRecvStream = QuicRecvStream;
+pub(crate) type RawSendStream = QuicSendStream;
+
+type RecvBuf = <RawRecvStream as quic::RecvStream>::Buf;
+type RecvError = <RawRecvStream as quic::RecvStream>::Error;
+type SendError = <RawSendStream as quic::SendStream<Bytes>>::Error;
+
+/// Error of a stream handed to a handler.
+#[derive(Debug)]
+pub enum StreamError<E> {
+    /// The stream went over a byte cap or the byte rate of its session. The receive side was
+    /// stopped and, for a bidirectional stream, the send side reset with
+    /// `LimitExceeded::error_code`.
+    Limit(LimitExceeded),
+    Quic(E),
+}
+
+impl<E: quic::Error> StreamError<E> {
+    /// HTTP/3 error code the stream was stopped or reset with.
+    pub fn err_code(&self) -> Option<u64> {
+        match self {
+            Self::Limit(exceeded) => Some(webtransport_error_code(exceeded.error_code())),
+            Self::Quic(err) => err.err_code(),
+        }
+    }
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for StreamError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Limit(exceeded) => write!(f, "stream stopped, {exceeded}"),
+            Self::Quic(err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for StreamError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Limit(exceeded) => Some(exceeded),
+            Self::Quic(err) => Some(err),
+        }
+    }
+}
+
+impl<E: Into<io::Error>> From<StreamError<E>> for io::Error {
+    fn from(err: StreamError<E>) -> Self {
+        match err {
+            StreamError::Limit(exceeded) => io::Error::new(io::ErrorKind::Other, exceeded),
+            StreamError::Quic(err) => err.into(),
+        }
+    }
+}
+
+// Set by the receive half of a stream once it went over a limit, so the send half of a
+// bidirectional stream is reset too.
+#[derive(Debug, Default)]
+pub(crate) struct Cutoff(OnceLock<LimitExceeded>);
+
+impl Cutoff {
+    fn get(&self) -> Option<LimitExceeded> {
+        self.0.get().copied()
+    }
+}
+
+/// Receive side of a WebTransport stream.
+///
+/// Every chunk is charged to the byte caps and byte rate of the session, and counted in the
+/// stream byte metrics, before the handler sees it. Over a cap, or over the rate with
+/// `LimitAction::Reset`, the stream is stopped and reads fail with `StreamError::Limit`. Over
+/// the rate with `LimitAction::Drop`, the chunk is held back until the budget covers it.
+///
+/// Read it chunk by chunk with `read_chunk`, or as an `AsyncRead`, where errors turn into
+/// `io::Error`s.
+pub struct RecvStream {
+    inner: RawRecvStream,
+    limits: StreamLimits,
+    cutoff: Arc<Cutoff>,
+    delayed: Option<(RecvBuf, Pin<Box<Sleep>>)>,
+    // Rest of a chunk that did not fit the buffer of `poll_read`.
+    unread: RecvBuf,
+    metrics: Arc<Metrics>,
+}
+
+impl RecvStream {
+    pub(crate) fn new(
+        inner: RawRecvStream,
+        limits: &Arc<SessionLimits>,
+        cutoff: Arc<Cutoff>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            inner,
+            limits: limits.stream(),
+            cutoff,
+            delayed: None,
+            unread: RecvBuf::new(),
+            metrics,
+        }
+    }
+
+    /// Reads the next chunk of the stream, or `None` once the peer finished it.
+    pub async fn read_chunk(&mut self) -> Result<Option<RecvBuf>, StreamError<RecvError>> {
+        poll_fn(|cx| self.poll_data(cx)).await
+    }
+
+    pub fn poll_data(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<RecvBuf>, StreamError<RecvError>>> {
+        if let Some(exceeded) = self.cutoff.get() {
+            return Poll::Ready(Err(StreamError::Limit(exceeded)));
+        }
+        if !self.unread.is_empty() {
+            return Poll::Ready(Ok(Some(std::mem::take(&mut self.unread))));
+        }
+        if let Some((_, sleep)) = &mut self.delayed {
+            ready!(sleep.as_mut().poll(cx));
+            return Poll::Ready(Ok(self.delayed.take().map(|(chunk, _)| chunk)));
+        }
+        let chunk = match ready!(self.inner.poll_data(cx)) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return Poll::Ready(Ok(None)),
+            Err(err) => return Poll::Ready(Err(StreamError::Quic(err))),
+        };
+        let charged = self.limits.charge(chunk.remaining());
+        if charged.is_ok() {
+            self.metrics.stream_bytes_received(chunk.remaining());
+        }
+        match charged {
+            Ok(delay) if delay.is_zero() => Poll::Ready(Ok(Some(chunk))),
+            Ok(delay) => {
+                self.delayed = Some((chunk, Box::pin(tokio::time::sleep(delay))));
+                self.poll_data(cx)
+            }
+            Err(exceeded) => {
+                info!("Stopping stream, {exceeded}");
+                self.inner
+                    .stop_sending(webtransport_error_code(exceeded.error_code()));
+                let _ = self.cutoff.0.set(exceeded);
+                Poll::Ready(Err(StreamError::Limit(exceeded)))
+            }
+        }
+    }
+
+    pub fn stop_sending(&mut self, error_code: u64) {
+        self.inner.stop_sending(error_code)
+    }
+
+    pub fn recv_id(&self) -> quic::StreamId {
+        self.inner.recv_id()
+    }
+}
+
+impl AsyncRead for RecvStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(mut chunk) = ready!(this.poll_data(cx))? else {
+            return Poll::Ready(Ok(()));
+        };
+        let len = chunk.len().min(buf.remaining());
+        buf.put_slice(&chunk.split_to(len));
+        this.unread = chunk;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Send side of a WebTransport stream. When the receive side of the same bidirectional stream
+/// goes over a limit, this side is reset, and writes fail with `StreamError::Limit`.
+///
+/// Write it with `write_all` and `finish`, or as an `AsyncWrite`, whose shutdown finishes it.
+pub struct SendStream {
+    inner: RawSendStream,
+    cutoff: Arc<Cutoff>,
+    reset: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl SendStream {
+    pub(crate) fn new(inner: RawSendStream, cutoff: Arc<Cutoff>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner,
+            cutoff,
+            reset: false,
+            metrics,
+        }
+    }
+
+    fn check_cutoff(&mut self) -> Result<(), StreamError<SendError>> {
+        match self.cutoff.get() {
+            Some(exceeded) => {
+                self.reset(webtransport_error_code(exceeded.error_code()));
+                Err(StreamError::Limit(exceeded))
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn poll_send<D: Buf>(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut D,
+    ) -> Poll<Result<usize, StreamError<SendError>>> {
+        self.check_cutoff()?;
+        let sent = ready!(self.inner.poll_send(cx, buf)).map_err(StreamError::Quic)?;
+        self.metrics.stream_bytes_sent(sent);
+        Poll::Ready(Ok(sent))
+    }
+
+    pub fn poll_finish(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), StreamError<SendError>>> {
+        self.check_cutoff()?;
+        self.inner.poll_finish(cx).map_err(StreamError::Quic)
+    }
+
+    /// Writes all of `buf`, waiting for flow control to let it through.
+    pub async fn write_all(&mut self, mut buf: impl Buf) -> Result<(), StreamError<SendError>> {
+        while buf.has_remaining() {
+            poll_fn(|cx| self.poll_send(cx, &mut buf)).await?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the stream, and waits until the peer received all of it.
+    pub async fn finish(&mut self) -> Result<(), StreamError<SendError>> {
+        poll_fn(|cx| self.poll_finish(cx)).await
+    }
+
+    pub fn reset(&mut self, reset_code: u64) {
+        if !self.reset {
+            self.reset = true;
+            self.inner.reset(reset_code);
+        }
+    }
+
+    pub fn send_id(&self) -> quic::StreamId {
+        self.inner.send_id()
+    }
+}
+
+impl AsyncWrite for SendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(ready!(self.get_mut().poll_send(cx, &mut buf))?))
+    }
+
+    // Writes go straight to the QUIC stream, which sends them as flow control allows.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(ready!(self.get_mut().poll_finish(cx))?))
+    }
+}
+
+// Dropping an unfinished stream would finish it, which must not pass for a complete reply
+// once the request went over a limit.
+impl Drop for SendStream {
+    fn drop(&mut self) {
+        let _ = self.check_cutoff();
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{bail, Context, Result};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use std::str::FromStr;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// How log lines are written to stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, with an event for every span that opens and closes.
+    #[default]
+    Text,
+    /// One JSON object per line, carrying the fields of every span it was logged in.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("unknown log format {s:?}, expected text or json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryOpt {
+    pub log_format: LogFormat,
+    /// OTLP/gRPC collector that spans are exported to, e.g. `http://localhost:4317`.
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of the exported spans.
+    pub service_name: String,
+}
+
+/// Keeps the OTLP exporter running. Call `shutdown` before exiting so buffered spans are sent.
+#[derive(Debug)]
+pub struct Telemetry {
+    exporting: bool,
+}
+
+impl Telemetry {
+    pub async fn shutdown(self) {
+        if self.exporting {
+            // Flushing blocks until the batch task, which runs on this runtime, has exported.
+            let _ =
+                tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+        }
+    }
+}
+
+/// Installs the global tracing subscriber: logs in the configured format on stderr, filtered
+/// by `RUST_LOG`, and spans exported over OTLP when an endpoint is set.
+///
+/// Must be called from within the Tokio runtime the server runs on.
+pub fn init_telemetry(opt: &TelemetryOpt) -> Result<Telemetry> {
+    let fmt = match opt.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_span_events(FmtSpan::FULL)
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+    let otlp = match &opt.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+                    "service.name",
+                    opt.service_name.clone(),
+                )])))
+                .install_batch(runtime::Tokio)
+                .with_context(|| format!("failed to set up OTLP export to {endpoint}"))?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt)
+        .with(otlp)
+        .try_init()
+        .context("failed to install the tracing subscriber")?;
+    Ok(Telemetry {
+        exporting: opt.otlp_endpoint.is_some(),
+    })
+}
@@ -0,0 +1,623 @@
+use super::certs::{get_key_and_cert_chain, Certs};
+use super::shutdown::Shutdown;
+use anyhow::{Context, Result};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+    ClientHello, NoClientAuth, ResolvesServerCert,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, KeyLog, PrivateKey, RootCertStore};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+// Browsers only accept `serverCertificateHashes` for certificates valid for at most 14 days.
+const MAX_SELF_SIGNED_VALIDITY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+// How often a generated certificate is checked for rotation when no poll interval is set.
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether QUIC clients have to present a certificate issued by one of the client CAs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientAuthMode {
+    #[default]
+    Off,
+    /// Certificates are verified when presented, but anonymous clients such as browsers are
+    /// still accepted.
+    Optional,
+    /// Handshakes without a valid client certificate fail.
+    Required,
+}
+
+impl FromStr for ClientAuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Self::Off),
+            "optional" => Ok(Self::Optional),
+            "required" => Ok(Self::Required),
+            _ => {
+                anyhow::bail!("unknown client auth mode {s:?}, expected off, optional or required")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientAuthOpt {
+    pub mode: ClientAuthMode,
+    /// CA certificates trusted to issue client certificates, PEM or DER. Required unless
+    /// `mode` is `Off`.
+    pub ca_certs: Option<PathBuf>,
+}
+
+impl ClientAuthOpt {
+    pub(crate) fn verifier(&self) -> Result<Arc<dyn ClientCertVerifier>> {
+        let roots = || -> Result<RootCertStore> {
+            let path = self
+                .ca_certs
+                .as_ref()
+                .context("client authentication requires a client CA bundle")?;
+            load_roots(path)
+        };
+        Ok(match self.mode {
+            ClientAuthMode::Off => NoClientAuth::boxed(),
+            ClientAuthMode::Optional => {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots()?).boxed()
+            }
+            ClientAuthMode::Required => AllowAnyAuthenticatedClient::new(roots()?).boxed(),
+        })
+    }
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let bundle = std::fs::read(path).context("failed to read client CA bundle")?;
+    let certs = if path.extension().map_or(false, |x| x == "der") {
+        vec![bundle]
+    } else {
+        rustls_pemfile::certs(&mut &*bundle).context("invalid PEM-encoded client CA bundle")?
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots
+            .add(&Certificate(cert))
+            .context("invalid client CA certificate")?;
+    }
+    if roots.is_empty() {
+        anyhow::bail!("no certificates found in client CA bundle {path:?}");
+    }
+    info!(
+        "Loaded {} client CA certificates from {path:?}",
+        roots.len()
+    );
+    Ok(roots)
+}
+
+/// A certificate generated at startup for local development, so browsers can connect with
+/// `serverCertificateHashes` instead of trusting a checked-in certificate.
+#[derive(Debug, Clone)]
+pub struct SelfSignedOpt {
+    /// DNS names and IP addresses the certificate is issued for.
+    pub subject_alt_names: Vec<String>,
+    /// Must not exceed 14 days. The certificate is replaced once half of it has passed.
+    pub validity: Duration,
+}
+
+impl Default for SelfSignedOpt {
+    fn default() -> Self {
+        Self {
+            subject_alt_names: vec!["localhost".into(), "127.0.0.1".into(), "::1".into()],
+            validity: Duration::from_secs(10 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CertSource {
+    Files(Certs),
+    SelfSigned(SelfSignedOpt),
+}
+
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    not_after: Option<SystemTime>,
+    modified: Option<SystemTime>,
+    sha256: Vec<u8>,
+}
+
+/// A certificate served to clients asking for a matching server name. `server_name` may
+/// start with `*.` to match any single label, e.g. `*.webtransport.rs`.
+#[derive(Debug, Clone)]
+pub struct SniCert {
+    pub server_name: String,
+    pub certs: Certs,
+}
+
+impl FromStr for SniCert {
+    type Err = anyhow::Error;
+
+    /// Parses `server_name=cert,key`, or `server_name=bundle.p12` for a PKCS#12 bundle
+    /// without a password.
+    fn from_str(s: &str) -> Result<Self> {
+        let (server_name, material) = s
+            .split_once('=')
+            .with_context(|| format!("expected server_name=cert,key, got {s:?}"))?;
+        let (cert, key) = match material.split_once(',') {
+            Some((cert, key)) => (cert, Some(key.trim().into())),
+            None => (material, None),
+        };
+        Ok(Self {
+            server_name: server_name.trim().to_string(),
+            certs: Certs {
+                cert: cert.trim().into(),
+                key,
+                password: None,
+            },
+        })
+    }
+}
+
+// A certificate together with where it comes from, so it can be reloaded in place.
+struct CertEntry {
+    source: CertSource,
+    current: RwLock<LoadedCert>,
+}
+
+impl CertEntry {
+    fn new(source: CertSource) -> Result<Self> {
+        let loaded = load(&source)?;
+        log_loaded(&source, &loaded);
+        Ok(Self {
+            source,
+            current: RwLock::new(loaded),
+        })
+    }
+
+    fn key(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().key.clone()
+    }
+
+    fn not_after(&self) -> Option<SystemTime> {
+        self.current.read().unwrap().not_after
+    }
+
+    fn reload(&self) {
+        match load(&self.source) {
+            Ok(loaded) => {
+                log_loaded(&self.source, &loaded);
+                *self.current.write().unwrap() = loaded;
+            }
+            Err(err) => {
+                error!("Failed to reload TLS certificate, keeping the current one: {err:?}");
+            }
+        }
+    }
+
+    fn poll(&self) {
+        if self.changed_on_disk() {
+            info!("TLS certificate changed on disk, reloading");
+            self.reload();
+        } else if self.needs_rotation() {
+            info!("Self-signed certificate is halfway to expiry, rotating");
+            self.reload();
+        }
+    }
+
+    fn changed_on_disk(&self) -> bool {
+        let CertSource::Files(certs) = &self.source else {
+            return false;
+        };
+        let modified = certs.modified();
+        modified.is_some() && modified != self.current.read().unwrap().modified
+    }
+
+    // Generated certificates are replaced halfway through their validity, leaving clients
+    // plenty of time to fetch the new hash before the old certificate expires.
+    fn needs_rotation(&self) -> bool {
+        let CertSource::SelfSigned(opt) = &self.source else {
+            return false;
+        };
+        let Some(not_after) = self.not_after() else {
+            return true;
+        };
+        let remaining = not_after
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        remaining < opt.validity / 2
+    }
+}
+
+/// Serves the certificate loaded from `Certs` and swaps it in place when the files change, so
+/// new handshakes pick up a rotated certificate without restarting the server.
+///
+/// Additional certificates can be registered per server name. Clients that send no SNI, or
+/// ask for a name without its own certificate, get the default one.
+pub struct CertResolver {
+    default: CertEntry,
+    sni: Vec<(String, CertEntry)>,
+}
+
+impl std::fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let server_names: Vec<_> = self.sni.iter().map(|(name, _)| name).collect();
+        f.debug_struct("CertResolver")
+            .field("default", &self.default.source)
+            .field("server_names", &server_names)
+            .field("not_after", &self.not_after())
+            .finish()
+    }
+}
+
+impl CertResolver {
+    pub fn new(certs: Certs) -> Result<Self> {
+        Self::with_default(CertSource::Files(certs))
+    }
+
+    /// Generates an ECDSA P-256 certificate instead of reading one from disk.
+    pub fn self_signed(opt: SelfSignedOpt) -> Result<Self> {
+        if opt.validity > MAX_SELF_SIGNED_VALIDITY {
+            anyhow::bail!(
+                "self-signed certificates must be valid for at most 14 days, got {:?}",
+                opt.validity
+            );
+        }
+        Self::with_default(CertSource::SelfSigned(opt))
+    }
+
+    fn with_default(source: CertSource) -> Result<Self> {
+        Ok(Self {
+            default: CertEntry::new(source)?,
+            sni: Vec::new(),
+        })
+    }
+
+    /// Serves `cert` to clients asking for its server name. Exact names take precedence over
+    /// wildcards.
+    pub fn with_sni_cert(mut self, cert: SniCert) -> Result<Self> {
+        let server_name = cert.server_name.to_ascii_lowercase();
+        let entry = CertEntry::new(CertSource::Files(cert.certs))
+            .with_context(|| format!("failed to load certificate for {server_name}"))?;
+        self.sni.push((server_name, entry));
+        Ok(self)
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &CertEntry> {
+        std::iter::once(&self.default).chain(self.sni.iter().map(|(_, entry)| entry))
+    }
+
+    fn select(&self, server_name: Option<&str>) -> &CertEntry {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return &self.default;
+        };
+        self.sni
+            .iter()
+            .find(|(pattern, _)| *pattern == name)
+            .or_else(|| {
+                self.sni
+                    .iter()
+                    .find(|(pattern, _)| matches_wildcard(pattern, &name))
+            })
+            .map_or(&self.default, |(_, entry)| entry)
+    }
+
+    /// Earliest expiry of the certificates served, for monitoring.
+    pub fn not_after(&self) -> Option<SystemTime> {
+        self.entries().filter_map(CertEntry::not_after).min()
+    }
+
+    /// SHA-256 of the default leaf certificate, as passed to `serverCertificateHashes`.
+    pub fn certificate_hash(&self) -> Vec<u8> {
+        self.default.current.read().unwrap().sha256.clone()
+    }
+
+    /// Reloads every certificate and key from disk, or generates a new self-signed
+    /// certificate. On failure the current certificate is kept.
+    pub fn reload(&self) {
+        self.entries().for_each(CertEntry::reload);
+    }
+
+    /// Reloads the certificates on SIGHUP, and whenever the files change when `poll_interval`
+    /// is set. Kubernetes updates mounted secrets by swapping a symlink, which polling the
+    /// modification time picks up reliably. Self-signed certificates are always checked for
+    /// rotation.
+    pub fn watch(self: Arc<Self>, poll_interval: Option<Duration>, shutdown: Shutdown) {
+        let poll_interval = match self.default.source {
+            CertSource::SelfSigned(_) => Some(poll_interval.unwrap_or(ROTATION_CHECK_INTERVAL)),
+            CertSource::Files(_) => poll_interval,
+        };
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut sighup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+            let mut ticker = poll_interval.map(tokio::time::interval);
+            loop {
+                let hangup = async {
+                    #[cfg(unix)]
+                    if let Some(sighup) = sighup.as_mut() {
+                        sighup.recv().await;
+                        return;
+                    }
+                    std::future::pending::<()>().await
+                };
+                let tick = async {
+                    match ticker.as_mut() {
+                        Some(ticker) => ticker.tick().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    _ = hangup => {
+                        info!("Received SIGHUP, reloading TLS certificates");
+                        self.reload();
+                    }
+                    _ = tick => self.entries().for_each(CertEntry::poll),
+                    _ = shutdown.wait() => break,
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.select(client_hello.server_name()).key())
+    }
+}
+
+// `*.example.com` matches exactly one extra label, so neither `example.com` nor
+// `a.b.example.com`.
+fn matches_wildcard(pattern: &str, name: &str) -> bool {
+    let Some(suffix) = pattern.strip_prefix("*.") else {
+        return false;
+    };
+    name.split_once('.')
+        .map_or(false, |(label, rest)| !label.is_empty() && rest == suffix)
+}
+
+fn load(source: &CertSource) -> Result<LoadedCert> {
+    match source {
+        CertSource::Files(certs) => {
+            let modified = certs.modified();
+            let (key, chain) = get_key_and_cert_chain(certs)?;
+            certified_key(key, chain, modified)
+        }
+        CertSource::SelfSigned(opt) => {
+            let (key, chain) = generate_self_signed(opt)?;
+            certified_key(key, chain, None)
+        }
+    }
+}
+
+fn certified_key(
+    key: PrivateKey,
+    chain: Vec<Certificate>,
+    modified: Option<SystemTime>,
+) -> Result<LoadedCert> {
+    let leaf = chain.first().context("certificate chain is empty")?;
+    let not_after = not_after(&leaf.0);
+    let sha256 = ring::digest::digest(&ring::digest::SHA256, &leaf.0)
+        .as_ref()
+        .to_vec();
+    let signing_key =
+        rustls::sign::any_supported_type(&key).context("unsupported private key type")?;
+    Ok(LoadedCert {
+        key: Arc::new(CertifiedKey::new(chain, signing_key)),
+        not_after,
+        modified,
+        sha256,
+    })
+}
+
+fn generate_self_signed(opt: &SelfSignedOpt) -> Result<(PrivateKey, Vec<Certificate>)> {
+    let mut params = rcgen::CertificateParams::new(opt.subject_alt_names.clone());
+    params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+    let now = SystemTime::now();
+    params.not_before = now.into();
+    params.not_after = (now + opt.validity).into();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "webtransport.rs development");
+    let cert = rcgen::Certificate::from_params(params)
+        .context("failed to generate self-signed certificate")?;
+    let der = cert
+        .serialize_der()
+        .context("failed to serialize self-signed certificate")?;
+    Ok((
+        PrivateKey(cert.serialize_private_key_der()),
+        vec![Certificate(der)],
+    ))
+}
+
+fn log_loaded(source: &CertSource, loaded: &LoadedCert) {
+    let valid_until = loaded
+        .not_after
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or("unknown".to_string(), |t| t.as_secs().to_string());
+    match source {
+        CertSource::Files(certs) => info!(
+            "Loaded TLS certificate from {}, valid until {} (unix time)",
+            certs.cert, valid_until
+        ),
+        CertSource::SelfSigned(opt) => info!(
+            "Generated self-signed certificate for {:?}, valid until {} (unix time), SHA-256 {}",
+            opt.subject_alt_names,
+            valid_until,
+            hex(&loaded.sha256)
+        ),
+    }
+}
+
+/// Appends TLS secrets to a file in the NSS key log format, which Wireshark uses to decrypt
+/// captured QUIC packets. Anyone who can read the file can decrypt the traffic too.
+pub(crate) struct KeyLogFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    /// Opens the file for appending. On unix it is created readable by the owner only, and an
+    /// existing file readable by anyone else is refused.
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("failed to open key log file {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Checked on the opened file, so it cannot be swapped after the check.
+            let mode = file
+                .metadata()
+                .with_context(|| format!("failed to stat key log file {}", path.display()))?
+                .permissions()
+                .mode();
+            anyhow::ensure!(
+                mode & 0o077 == 0,
+                "key log file {} has mode {:o}, it must only be accessible by its owner",
+                path.display(),
+                mode & 0o777
+            );
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl std::fmt::Debug for KeyLogFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyLogFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{label} {} {}\n", hex(client_random), hex(secret));
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to write key log {}: {err}", self.path.display());
+        }
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub(crate) fn subject(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    Some(cert.subject().to_string())
+}
+
+pub(crate) fn not_after(der: &[u8]) -> Option<SystemTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let timestamp = cert.validity().not_after.timestamp();
+    Some(UNIX_EPOCH + Duration::from_secs(timestamp.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_signed(name: &str) -> CertEntry {
+        CertEntry::new(CertSource::SelfSigned(SelfSignedOpt {
+            subject_alt_names: vec![name.to_string()],
+            ..SelfSignedOpt::default()
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn wildcards_match_exactly_one_label() {
+        assert!(matches_wildcard("*.example.com", "a.example.com"));
+        assert!(!matches_wildcard("*.example.com", "example.com"));
+        assert!(!matches_wildcard("*.example.com", "a.b.example.com"));
+        assert!(!matches_wildcard("*.example.com", ".example.com"));
+        assert!(!matches_wildcard("*.example.com", "a.example.org"));
+        assert!(!matches_wildcard("example.com", "example.com"));
+    }
+
+    #[test]
+    fn select_prefers_exact_names_then_wildcards_then_the_default() {
+        let resolver = CertResolver {
+            default: self_signed("localhost"),
+            sni: vec![
+                ("*.example.com".to_string(), self_signed("*.example.com")),
+                (
+                    "api.example.com".to_string(),
+                    self_signed("api.example.com"),
+                ),
+            ],
+        };
+        let selected = |name| resolver.select(name) as *const CertEntry;
+        assert_eq!(
+            selected(Some("API.example.com")),
+            &resolver.sni[1].1 as *const _
+        );
+        assert_eq!(
+            selected(Some("www.example.com")),
+            &resolver.sni[0].1 as *const _
+        );
+        assert_eq!(
+            selected(Some("a.www.example.com")),
+            &resolver.default as *const _
+        );
+        assert_eq!(selected(Some("example.com")), &resolver.default as *const _);
+        assert_eq!(selected(None), &resolver.default as *const _);
+    }
+
+    #[test]
+    fn sni_certs_parse_from_settings() {
+        let cert: SniCert = " api.example.com = a.pem , a.key ".parse().unwrap();
+        assert_eq!(cert.server_name, "api.example.com");
+        assert!(cert.certs.key.is_some());
+        let bundle: SniCert = "*.example.com=bundle.p12".parse().unwrap();
+        assert!(bundle.certs.key.is_none());
+        assert!("missing-equals".parse::<SniCert>().is_err());
+    }
+
+    #[test]
+    fn self_signed_validity_is_capped() {
+        let opt = SelfSignedOpt {
+            validity: MAX_SELF_SIGNED_VALIDITY + Duration::from_secs(1),
+            ..SelfSignedOpt::default()
+        };
+        assert!(CertResolver::self_signed(opt).is_err());
+        let resolver = CertResolver::self_signed(SelfSignedOpt::default()).unwrap();
+        assert_eq!(resolver.certificate_hash().len(), 32);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_log_file_is_private_to_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("keylog");
+        let key_log = KeyLogFile::open(&path).unwrap();
+        key_log.log("CLIENT_RANDOM", &[0xab], &[0xcd]);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "CLIENT_RANDOM ab cd\n"
+        );
+
+        // Reopening appends to a private file, but refuses one others can read.
+        drop(key_log);
+        KeyLogFile::open(&path).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let err = KeyLogFile::open(&path).unwrap_err();
+        assert!(err.to_string().contains("644"), "{err}");
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Context, Result};
+use quinn::congestion::{BbrConfig, Controller, ControllerFactory, CubicConfig, NewRenoConfig};
+use quinn::{MtuDiscoveryConfig, TransportConfig, VarInt};
+use quinn_proto::RttEstimator;
+use std::any::Any;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Value of `SwitchableController::requested` when no switch is pending.
+const NO_SWITCH: u8 = 0;
+
+/// Congestion control algorithm of a QUIC connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CongestionController {
+    NewReno = 1,
+    #[default]
+    Cubic = 2,
+    Bbr = 3,
+}
+
+impl FromStr for CongestionController {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "newreno" | "new_reno" => Ok(Self::NewReno),
+            "cubic" => Ok(Self::Cubic),
+            "bbr" => Ok(Self::Bbr),
+            _ => {
+                anyhow::bail!("unknown congestion controller {s:?}, expected newreno, cubic or bbr")
+            }
+        }
+    }
+}
+
+impl CongestionController {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::NewReno),
+            2 => Some(Self::Cubic),
+            3 => Some(Self::Bbr),
+            _ => None,
+        }
+    }
+
+    fn build(self, now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        match self {
+            Self::NewReno => Arc::new(NewRenoConfig::default()).build(now, current_mtu),
+            Self::Cubic => Arc::new(CubicConfig::default()).build(now, current_mtu),
+            Self::Bbr => Arc::new(BbrConfig::default()).build(now, current_mtu),
+        }
+    }
+}
+
+/// QUIC transport parameters. Fields left as `None` keep quinn's defaults.
+#[derive(Debug, Clone)]
+pub struct TransportOpt {
+    pub max_concurrent_bidi_streams: Option<u32>,
+    pub max_concurrent_uni_streams: Option<u32>,
+    /// Bytes a peer may send on one stream before it is read.
+    pub stream_receive_window: Option<u64>,
+    /// Bytes a peer may send across all streams before they are read.
+    pub receive_window: Option<u64>,
+    pub send_window: Option<u64>,
+    /// Bytes of incoming datagrams buffered before the oldest are dropped.
+    pub datagram_receive_buffer_size: Option<usize>,
+    pub datagram_send_buffer_size: Option<usize>,
+    /// RTT assumed before the first sample is taken.
+    pub initial_rtt: Option<Duration>,
+    pub mtu_discovery: bool,
+    pub keep_alive_interval: Option<Duration>,
+    pub max_idle_timeout: Duration,
+    /// Used by every connection unless a session handler picks another one.
+    pub congestion_controller: CongestionController,
+}
+
+impl Default for TransportOpt {
+    fn default() -> Self {
+        Self {
+            max_concurrent_bidi_streams: None,
+            max_concurrent_uni_streams: None,
+            stream_receive_window: None,
+            receive_window: None,
+            send_window: None,
+            datagram_receive_buffer_size: None,
+            datagram_send_buffer_size: None,
+            initial_rtt: None,
+            mtu_discovery: true,
+            keep_alive_interval: Some(Duration::from_secs(2)),
+            max_idle_timeout: Duration::from_secs(10),
+            congestion_controller: CongestionController::default(),
+        }
+    }
+}
+
+impl TransportOpt {
+    pub(crate) fn transport_config(&self) -> Result<TransportConfig> {
+        let mut config = TransportConfig::default();
+        if let Some(value) = self.max_concurrent_bidi_streams {
+            config.max_concurrent_bidi_streams(value.into());
+        }
+        if let Some(value) = self.max_concurrent_uni_streams {
+            config.max_concurrent_uni_streams(value.into());
+        }
+        if let Some(value) = self.stream_receive_window {
+            config.stream_receive_window(
+                VarInt::from_u64(value).context("stream receive window is too large")?,
+            );
+        }
+        if let Some(value) = self.receive_window {
+            config.receive_window(VarInt::from_u64(value).context("receive window is too large")?);
+        }
+        if let Some(value) = self.send_window {
+            config.send_window(value);
+        }
+        if let Some(value) = self.datagram_receive_buffer_size {
+            config.datagram_receive_buffer_size(Some(value));
+        }
+        if let Some(value) = self.datagram_send_buffer_size {
+            config.datagram_send_buffer_size(value);
+        }
+        if let Some(value) = self.initial_rtt {
+            config.initial_rtt(value);
+        }
+        config.mtu_discovery_config(self.mtu_discovery.then(MtuDiscoveryConfig::default));
+        config.keep_alive_interval(self.keep_alive_interval);
+        config.max_idle_timeout(Some(
+            self.max_idle_timeout
+                .try_into()
+                .context("idle timeout is too large")?,
+        ));
+        config.congestion_controller_factory(Arc::new(SwitchableControllerFactory {
+            default: self.congestion_controller,
+        }));
+        Ok(config)
+    }
+}
+
+/// Replaces the congestion controller of a connection. The new controller starts over from
+/// its initial window the next time a packet is sent or acknowledged.
+pub(crate) fn set_congestion_controller(
+    conn: &quinn::Connection,
+    controller: CongestionController,
+) -> Result<()> {
+    // `congestion_state` returns a clone, which shares the pending switch with the original.
+    let state = conn
+        .congestion_state()
+        .into_any()
+        .downcast::<SwitchableController>()
+        .map_err(|_| {
+            anyhow!("connection was not created with a switchable congestion controller")
+        })?;
+    state.requested.store(controller as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+struct SwitchableControllerFactory {
+    default: CongestionController,
+}
+
+impl ControllerFactory for SwitchableControllerFactory {
+    fn build(&self, now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        Box::new(SwitchableController {
+            inner: self.default.build(now, current_mtu),
+            requested: Arc::new(AtomicU8::new(NO_SWITCH)),
+            current_mtu,
+        })
+    }
+}
+
+// quinn picks the controller when the connection is created, before the handler is known, so
+// it wraps one that can be swapped later.
+struct SwitchableController {
+    inner: Box<dyn Controller>,
+    requested: Arc<AtomicU8>,
+    current_mtu: u16,
+}
+
+impl SwitchableController {
+    fn switch_if_requested(&mut self, now: Instant) {
+        let requested = self.requested.swap(NO_SWITCH, Ordering::Relaxed);
+        if let Some(controller) = CongestionController::from_u8(requested) {
+            self.inner = controller.build(now, self.current_mtu);
+        }
+    }
+}
+
+impl Controller for SwitchableController {
+    fn on_sent(&mut self, now: Instant, bytes: u64, last_packet_number: u64) {
+        self.switch_if_requested(now);
+        self.inner.on_sent(now, bytes, last_packet_number)
+    }
+
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &RttEstimator,
+    ) {
+        self.switch_if_requested(now);
+        self.inner.on_ack(now, sent, bytes, app_limited, rtt)
+    }
+
+    fn on_end_acks(
+        &mut self,
+        now: Instant,
+        in_flight: u64,
+        app_limited: bool,
+        largest_packet_num_acked: Option<u64>,
+    ) {
+        self.inner
+            .on_end_acks(now, in_flight, app_limited, largest_packet_num_acked)
+    }
+
+    fn on_congestion_event(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        is_persistent_congestion: bool,
+        lost_bytes: u64,
+    ) {
+        self.inner
+            .on_congestion_event(now, sent, is_persistent_congestion, lost_bytes)
+    }
+
+    fn on_mtu_update(&mut self, new_mtu: u16) {
+        self.current_mtu = new_mtu;
+        self.inner.on_mtu_update(new_mtu)
+    }
+
+    fn window(&self) -> u64 {
+        self.inner.window()
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(Self {
+            inner: self.inner.clone_box(),
+            requested: self.requested.clone(),
+            current_mtu: self.current_mtu,
+        })
+    }
+
+    fn initial_window(&self) -> u64 {
+        self.inner.initial_window()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
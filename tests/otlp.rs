@@ -0,0 +1,260 @@
+//! Runs the WebTransport server with OTLP export pointed at an in-process collector, and checks
+//! that the connection, session and stream spans of an echoed stream arrive with their
+//! attributes.
+#![cfg(feature = "ssr")]
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{Method, Request, StatusCode};
+use leptos_actix_webtransport_template::webtransport_server::*;
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{
+    TraceService, TraceServiceServer,
+};
+use opentelemetry_proto::tonic::collector::trace::v1::{
+    ExportTraceServiceRequest, ExportTraceServiceResponse,
+};
+use opentelemetry_proto::tonic::common::v1::any_value::Value;
+use opentelemetry_proto::tonic::trace::v1::Span;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use sec_http3::ext::Protocol;
+use sec_http3::sec_http3_quinn as h3_quinn;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_stream::wrappers::TcpListenerStream;
+
+// Signal that opens a WebTransport bidirectional stream, from draft-ietf-webtrans-http3, as a
+// two byte varint.
+const WEBTRANSPORT_STREAM: u16 = 0x4000 | 0x41;
+const PAYLOAD: &[u8] = b"traced";
+
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Vec<Span>>>);
+
+#[tonic::async_trait]
+impl TraceService for Collector {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportTraceServiceRequest>,
+    ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        let spans = request
+            .into_inner()
+            .resource_spans
+            .into_iter()
+            .flat_map(|resource| resource.scope_spans)
+            .flat_map(|scope| scope.spans);
+        self.0.lock().unwrap().extend(spans);
+        Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+    }
+}
+
+fn attribute<'a>(span: &'a Span, key: &str) -> Option<&'a Value> {
+    span.attributes
+        .iter()
+        .find(|attribute| attribute.key == key)?
+        .value
+        .as_ref()?
+        .value
+        .as_ref()
+}
+
+fn string_attribute<'a>(span: &'a Span, key: &str) -> &'a str {
+    match attribute(span, key) {
+        Some(Value::StringValue(value)) => value,
+        other => panic!("span {} has {key} = {other:?}", span.name),
+    }
+}
+
+fn free_udp_port() -> u16 {
+    UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn free_tcp_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn wait_until_ready(health: SocketAddr) {
+    for _ in 0..100 {
+        if let Ok(mut stream) = tokio::net::TcpStream::connect(health).await {
+            let _ = stream.write_all(b"GET /readyz HTTP/1.0\r\n\r\n").await;
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response).await;
+            if response.starts_with("HTTP/1.0 200") || response.starts_with("HTTP/1.1 200") {
+                return;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("server did not become ready");
+}
+
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Opens a session to `/echo`, echoes one bidirectional stream through it and closes the
+// connection. Returns the local port of the client.
+async fn echo_through_session(target: SocketAddr) -> u16 {
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    let mut endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls_config)));
+    let conn = endpoint
+        .connect(target, "localhost")
+        .unwrap()
+        .await
+        .unwrap();
+
+    let (mut driver, mut send_request) = sec_http3::client::builder()
+        .enable_extended_connect(true)
+        .enable_datagram(true)
+        .build::<_, _, Bytes>(h3_quinn::Connection::new(conn.clone()))
+        .await
+        .unwrap();
+    let driver = tokio::spawn(async move { driver.wait_idle().await });
+    let request = Request::builder()
+        .method(Method::CONNECT)
+        .uri(format!("https://localhost:{}/echo", target.port()))
+        .extension(Protocol::WEB_TRANSPORT)
+        .body(())
+        .unwrap();
+    let mut connect = send_request.send_request(request).await.unwrap();
+    let response = connect.recv_response().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Client-initiated bidirectional streams have ids 4n, and a session is named after the id
+    // of its CONNECT stream.
+    let session_id = connect.id().index() << 2;
+    let (mut send, mut recv) = conn.open_bi().await.unwrap();
+    let mut header = BytesMut::new();
+    header.put_u16(WEBTRANSPORT_STREAM);
+    // Two byte varint, enough for the first streams of a connection.
+    header.put_u16(0x4000 | session_id as u16);
+    send.write_all(&header).await.unwrap();
+    send.write_all(PAYLOAD).await.unwrap();
+    send.finish().await.unwrap();
+    let echoed = recv.read_to_end(PAYLOAD.len()).await.unwrap();
+    assert_eq!(echoed, PAYLOAD);
+
+    let port = endpoint.local_addr().unwrap().port();
+    conn.close(0u32.into(), b"done");
+    driver.abort();
+    endpoint.wait_idle().await;
+    port
+}
+
+#[actix_rt::test]
+async fn spans_reach_the_collector() {
+    let collector = Collector::default();
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let collector_addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(TraceServiceServer::new(collector.clone()))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    // Spans are only recorded at the levels `RUST_LOG` lets through.
+    std::env::set_var("RUST_LOG", "info");
+    let telemetry = init_telemetry(&TelemetryOpt {
+        log_format: LogFormat::Text,
+        otlp_endpoint: Some(format!("http://{collector_addr}")),
+        service_name: "otlp-test".into(),
+    })
+    .unwrap();
+
+    let listen: SocketAddr = (Ipv4Addr::LOCALHOST, free_udp_port()).into();
+    let health_listen: SocketAddr = (Ipv4Addr::LOCALHOST, free_tcp_port()).into();
+    let opt = WebTransportOpt {
+        listen,
+        health_listen,
+        certs: Certs {
+            cert: "unused.pem".into(),
+            key: None,
+            password: None,
+        },
+        self_signed: Some(SelfSignedOpt::default()),
+        sni_certs: Vec::new(),
+        client_auth: ClientAuthOpt::default(),
+        zero_rtt: false,
+        allowed_origins: Vec::new(),
+        token_auth: None,
+        cert_reload_interval: None,
+        max_sessions_per_connection: 4,
+        transport: TransportOpt::default(),
+        rate_limits: RateLimitOpt::default(),
+        site: None,
+        alt_svc: AltSvcOpt::default(),
+        shutdown_grace_period: Duration::from_secs(1),
+        readiness_check_path: None,
+        key_log_file: None,
+        qlog_dir: None,
+    };
+    let shutdown = Shutdown::new();
+    let server = {
+        let shutdown = shutdown.clone();
+        let router = Router::new().route("/echo", EchoHandler);
+        actix_rt::spawn(async move { start(opt, router, shutdown).await.unwrap() })
+    };
+    wait_until_ready(health_listen).await;
+
+    let client_port = echo_through_session(listen).await;
+    // Lets the server notice the closed connection and end its spans.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    shutdown.trigger();
+    server.await.unwrap();
+    telemetry.shutdown().await;
+
+    let spans = collector.0.lock().unwrap().clone();
+    let named = |name: &str| {
+        spans
+            .iter()
+            .find(|span| span.name == name)
+            .unwrap_or_else(|| panic!("no {name} span among {} exported", spans.len()))
+    };
+    let connection = named("connection");
+    let session = named("session");
+    let stream = named("stream");
+
+    assert!(matches!(
+        attribute(connection, "conn_id"),
+        Some(Value::IntValue(_))
+    ));
+    assert_eq!(
+        string_attribute(connection, "remote_addr"),
+        format!("127.0.0.1:{client_port}")
+    );
+
+    assert_eq!(string_attribute(session, "path"), "/echo");
+    assert!(!string_attribute(session, "session_id").is_empty());
+    assert_eq!(session.parent_span_id, connection.span_id);
+
+    assert_eq!(string_attribute(stream, "kind"), "bidi");
+    assert!(!string_attribute(stream, "stream_id").is_empty());
+    assert_eq!(stream.parent_span_id, session.span_id);
+}